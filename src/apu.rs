@@ -0,0 +1,312 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt, fs::File, io::Write};
+
+use crate::{config::{Config, Override, OverrideAddr}, incbin, line::Line, spc700::Instruction};
+
+/* Chains have no size of their own, this is as far as they're read looking for the end */
+const MAX_CHAIN: u64 = 0x80000;
+
+/* Part of a block: bytes left as they are, or SPC700 code at the ARAM address it runs from */
+#[derive(Debug, Clone)]
+pub enum Segment {
+    Data(Vec<u8>),
+    Code(u64, Vec<(Option<String>, String, Instruction)>)
+}
+
+/* A block of the upload, its size and ARAM destination and what it holds. The last one has a size of
+   zero and the address the SPC700 starts running at instead. */
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub address: u64,
+    pub size: u64,
+    pub destination: u64,
+    pub segments: Vec<Segment>
+}
+
+/* The blocks the APU upload routine sends one after the other, written as a record each. The labels
+   that sat inside are defined relative to the label at the start, like for incbin. */
+#[derive(Debug, Clone)]
+pub struct Chain {
+    pub base: String,
+    pub blocks: Vec<Block>,
+    pub labels: Vec<(String, u64)>
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, block) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if block.size == 0 {
+                write!(f, "    dw $0000,${:04X} ; end, entry point", block.destination)?;
+                continue;
+            }
+            write!(f, "    dw ${:04X},${:04X} ; size, destination", block.size, block.destination)?;
+
+            for segment in &block.segments {
+                match segment {
+                    Segment::Data(bytes) => for row in bytes.chunks(16) {
+                        let values: Vec<String> = row.iter().map(|b| format!("${:02X}", b)).collect();
+                        write!(f, "\n    db {}", values.join(","))?;
+                    },
+                    Segment::Code(aram, instructions) => {
+                        write!(f, "\n    arch spc700\n    base ${:04X}", aram)?;
+                        for (label, text, ins) in instructions {
+                            if let Some(label) = label {
+                                write!(f, "\n{}:", label)?;
+                            }
+                            write!(f, "\n    {:<40};| {:04X} |", text, ins.aram)?;
+                        }
+                        write!(f, "\n    base off\n    arch 65816")?;
+                    }
+                }
+            }
+        }
+        for (name, offset) in &self.labels {
+            write!(f, "\n{} = {}+{}", name, self.base, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ApuError {
+    Truncated { address: u64, block: u64 },
+    LocalLabel { address: u64 }
+}
+
+impl fmt::Display for ApuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApuError::Truncated { address, block } =>
+                write!(f, "${:06X}: the block at ${:06X} runs past the data, the chain is left as it is", address, block),
+            ApuError::LocalLabel { address } =>
+                write!(f, "${:06X}: has a local label inside, the chain is left as it is", address)
+        }
+    }
+}
+
+/* Instructions that never go on to the next one: ret, reti, brk, jmp, jmp [a+x], bra, sleep and stop */
+fn ends_flow(opcode: u8) -> bool {
+    matches!(opcode, 0x6F | 0x7F | 0x0F | 0x5F | 0x1F | 0x2F | 0xEF | 0xFF)
+}
+
+/* Where the instructions of a chain start, as the block they're in and their ARAM address. The ARAM
+   ranges in the override's "code" are read straight through, without them the code is whatever can
+   be reached from the entry point by following branches, jumps and calls, so data after the code
+   stays data. Blocks that get written over by later ones keep only what's left of them in ARAM. */
+fn code_starts(ov: &Override, blocks: &[(u64, u64, u64, &[u8])], entry: u64) -> HashSet<(usize, u64)> {
+    /* Every ARAM byte along with the block it came from */
+    let mut aram: Vec<Option<(u8, usize)>> = vec![None; 0x10000];
+    for (block, (_, _, dest, data)) in blocks.iter().enumerate() {
+        for (i, b) in data.iter().enumerate() {
+            if let Some(slot) = aram.get_mut(*dest as usize + i) {
+                *slot = Some((*b, block));
+            }
+        }
+    }
+    let decode = |a: u64| {
+        let bytes: Vec<(u8, usize)> = (a..a + 3).map_while(|a| aram.get(a as usize).copied().flatten()).collect();
+        Instruction::decode(&bytes.iter().map(|(b, _)| *b).collect::<Vec<u8>>(), a)
+            .map(|ins| (bytes[0].1, bytes[..ins.bytes.len()].iter().all(|(_, block)| *block == bytes[0].1), ins))
+    };
+
+    let mut starts = HashSet::new();
+    match &ov.code {
+        Some(ranges) => for r in ranges {
+            let mut a = r[0];
+            while a <= r[1] {
+                match decode(a) {
+                    Some((block, whole, ins)) => {
+                        if whole {
+                            starts.insert((block, a));
+                        }
+                        a += ins.bytes.len() as u64;
+                    },
+                    None => a += 1
+                }
+            }
+        },
+        None => {
+            let mut visited = HashSet::new();
+            let mut pending = vec![entry];
+            while let Some(a) = pending.pop() {
+                if !visited.insert(a) {
+                    continue;
+                }
+                let Some((block, whole, ins)) = decode(a) else {
+                    continue;
+                };
+                if whole {
+                    starts.insert((block, a));
+                }
+                pending.extend(ins.target());
+                if !ends_flow(ins.bytes[0]) {
+                    pending.push(a + ins.bytes.len() as u64);
+                }
+            }
+        }
+    }
+    starts
+}
+
+/* A byte or an instruction of a block at its ARAM address */
+type Part = (u64, Option<Instruction>, Vec<u8>);
+
+/* Splits a block into code and data at its instruction starts. Instructions that would run past the end of the block stay data. */
+fn segments(block: usize, dest: u64, bytes: &[u8], starts: &HashSet<(usize, u64)>) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let aram = dest + offset as u64;
+        let instruction = Some(aram)
+            .filter(|a| starts.contains(&(block, *a)))
+            .and_then(|a| Instruction::decode(&bytes[offset..], a));
+        let length = instruction.as_ref().map(|i| i.bytes.len()).unwrap_or(1);
+        parts.push((aram, instruction, bytes[offset..offset + length].to_vec()));
+        offset += length;
+    }
+    parts
+}
+
+/* Parses the chains of upload blocks starting at each "APUBlocks" override, the code in the
+   blocks disassembled as SPC700 with labels on everything that's branched, jumped or called to. */
+pub fn extract(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config) -> (Vec<(u64, Chain)>, Vec<ApuError>) {
+    let mut chains = Vec::new();
+    let mut errors = Vec::new();
+    let mut names: HashSet<String> = HashSet::new();
+
+    for ov in config.overrides.iter().filter(|ov| ov._type.as_deref() == Some("APUBlocks")) {
+        let start = match &ov.addr {
+            OverrideAddr::Range(r) => r[0],
+            OverrideAddr::Address(a) => *a
+        };
+        let bytes = incbin::read_bytes(lines, start, MAX_CHAIN);
+        let word = |o: usize| bytes.get(o..o + 2).map(|w| w[0] as u64 | ((w[1] as u64) << 8));
+
+        /* (offset, size, destination, data) for every block */
+        let mut raw: Vec<(u64, u64, u64, &[u8])> = Vec::new();
+        let mut offset = 0;
+        let entry = loop {
+            let (Some(size), Some(dest)) = (word(offset), word(offset + 2)) else {
+                break None;
+            };
+            if size == 0 {
+                break Some(dest);
+            }
+            let Some(data) = bytes.get(offset + 4..offset + 4 + size as usize) else {
+                break None;
+            };
+            raw.push((offset as u64, size, dest, data));
+            offset += 4 + size as usize;
+        };
+        let Some(entry) = entry else {
+            errors.push(ApuError::Truncated { address: start, block: offset_address(start, offset as u64) });
+            continue;
+        };
+        let length = offset as u64 + 4;
+        let end = offset_address(start, length);
+        let Some((base, inner)) = incbin::region_labels(start, end, "APU") else {
+            errors.push(ApuError::LocalLabel { address: start });
+            continue;
+        };
+
+        /* Instructions first, then labels for every target that's the start of one */
+        let starts = code_starts(ov, &raw, entry);
+        let parts: Vec<Vec<Part>> = raw.iter().enumerate().map(|(block, (_, _, dest, data))| segments(block, *dest, data, &starts)).collect();
+        let starts: HashSet<u64> = parts.iter().flatten().filter(|(_, i, _)| i.is_some()).map(|(a, _, _)| *a).collect();
+        let mut labels: HashMap<u64, String> = HashMap::new();
+        for target in parts.iter().flatten().filter_map(|(_, i, _)| i.as_ref().and_then(|i| i.target())).chain([entry]) {
+            if starts.contains(&target) && !labels.contains_key(&target) {
+                let mut name = format!("SPC_{:04X}", target);
+                let mut n = 2;
+                while names.contains(&name) {
+                    name = format!("SPC_{:04X}_{}", target, n);
+                    n += 1;
+                }
+                names.insert(name.to_string());
+                labels.insert(target, name);
+            }
+        }
+
+        let mut blocks: Vec<Block> = raw.iter().zip(parts).map(|((offset, size, dest, _), parts)| {
+            let mut segments: Vec<Segment> = Vec::new();
+            for (aram, instruction, bytes) in parts {
+                match (instruction, segments.last_mut()) {
+                    (Some(ins), Some(Segment::Code(_, list))) => {
+                        list.push((labels.get(&aram).cloned(), ins.format(&|t| labels.get(&t).cloned()), ins))
+                    },
+                    (Some(ins), _) => {
+                        let text = ins.format(&|t| labels.get(&t).cloned());
+                        segments.push(Segment::Code(aram, vec![(labels.get(&aram).cloned(), text, ins)]))
+                    },
+                    (None, Some(Segment::Data(data))) => data.extend(bytes),
+                    (None, _) => segments.push(Segment::Data(bytes))
+                }
+            }
+            Block { address: offset_address(start, *offset), size: *size, destination: *dest, segments }
+        }).collect();
+        blocks.push(Block { address: offset_address(start, offset as u64), size: 0, destination: entry, segments: Vec::new() });
+
+        let chain = Chain { base, blocks, labels: inner };
+        incbin::replace(lines, start, end, vec![Line::Apu(chain.clone())]);
+        chains.push((start, chain));
+    }
+    (chains, errors)
+}
+
+/* The ROM address "offset" bytes on from "start", LoROM banks only have their upper half */
+fn offset_address(start: u64, offset: u64) -> u64 {
+    let position = (start & 0x7FFF) + offset;
+    (((start >> 16) + position / 0x8000) << 16) | 0x8000 | (position % 0x8000)
+}
+
+/* Every chain with its blocks, what they hold and where they go */
+pub fn write_report(chains: &[(u64, Chain)], errors: &[ApuError], filename: &str) {
+    let mut report_file = File::create(filename).unwrap();
+    for (address, chain) in chains {
+        let _ = writeln!(report_file, "${:06X} {}:", address, chain.base);
+        for block in &chain.blocks {
+            let code: usize = block.segments.iter().map(|s| match s {
+                Segment::Code(_, list) => list.len(),
+                _ => 0
+            }).sum();
+            let _ = match (block.size, code) {
+                (0, _) => writeln!(report_file, "    ${:06X}: end, entry point ${:04X}", block.address, block.destination),
+                (size, 0) => writeln!(report_file, "    ${:06X}: ${:04X} bytes to ${:04X}", block.address, size, block.destination),
+                (size, code) => writeln!(report_file, "    ${:06X}: ${:04X} bytes to ${:04X}, {} instructions", block.address, size, block.destination, code)
+            };
+        }
+    }
+    for e in errors {
+        let _ = writeln!(report_file, "{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwritten_code() {
+        /* The second block writes over the middle of the first one's mov, only its ret is left whole */
+        let first: &[u8] = &[0x8F, 0x34, 0x12, 0x6F];
+        let second: &[u8] = &[0xE8, 0x00];
+        let blocks = [(0, 4, 0x0500, first), (8, 2, 0x0501, second)];
+        let starts = code_starts(&Override::default(), &blocks, 0x0500);
+        assert_eq!(starts, HashSet::from([(0, 0x0503)]));
+
+        let parts = segments(0, 0x0500, first, &starts);
+        let code: Vec<_> = parts.iter().filter(|(_, i, _)| i.is_some()).map(|(a, _, _)| *a).collect();
+        assert_eq!(code, [0x0503]);
+        assert!(segments(1, 0x0501, second, &starts).iter().all(|(_, i, _)| i.is_none()));
+    }
+
+    #[test]
+    fn code_ranges() {
+        let data: &[u8] = &[0xE8, 0x01, 0xC4, 0x10, 0x6F, 0x55];
+        let ov = Override { code: Some(vec![[0x0600, 0x0604]]), ..Default::default() };
+        let starts = code_starts(&ov, &[(0, 6, 0x0600, data)], 0x0600);
+        assert_eq!(starts, HashSet::from([(0, 0x0600), (0, 0x0602), (0, 0x0604)]));
+    }
+}
@@ -0,0 +1,81 @@
+use std::{collections::{BTreeMap, HashSet}, fs::File, io::Write};
+
+use crate::{config::Config, data::DataVal, label::{LabelType, LABELS}, line::Line, opcode::AddrMode};
+
+#[derive(Debug)]
+pub struct Literal {
+    pub address: u64,
+    pub value: u64,
+    pub kind: &'static str,
+    pub candidates: Vec<String>
+}
+
+/* Values in the ROM range that get written as plain numbers although a label sits at that
+   address. Each of them is a pointer that would go stale if the code or data moved. */
+pub fn relocation_audit(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<Literal> {
+    let mut literals = Vec::new();
+
+    for line in lines.values().flatten() {
+        match line {
+            Line::Code(c) if c.opcode.addr_mode == AddrMode::Immediate && c.length == 2 => {
+                let value = match c.operand_addr(config) {
+                    Some(v) if (v & 0xFFFF) >= 0x8000 => v,
+                    _ => continue
+                };
+                if c.operand_label(config).is_some() {
+                    continue;
+                }
+
+                let banks = [value >> 16, c.address >> 16];
+                let candidates = label_names(&banks, value & 0xFFFF);
+                if !candidates.is_empty() {
+                    literals.push(Literal { address: c.address, value: value & 0xFFFF, kind: "immediate", candidates });
+                }
+            },
+            Line::Data(d) => {
+                let symbolic: HashSet<u64> = d.pointers(config).iter().map(|(from, _)| *from).collect();
+                for (addr, val) in d.entries() {
+                    let value = match val {
+                        DataVal::DW(w) if *w >= 0x8000 => *w as u64,
+                        _ => continue
+                    };
+                    if symbolic.contains(&addr) {
+                        continue;
+                    }
+
+                    let db = config.get_override(addr).and_then(|ov| ov.db).unwrap_or(addr >> 16);
+                    let candidates = label_names(&[db, addr >> 16], value);
+                    if !candidates.is_empty() {
+                        literals.push(Literal { address: addr, value, kind: "data word", candidates });
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    literals
+}
+
+fn label_names(banks: &[u64], value: u64) -> Vec<String> {
+    let labels = LABELS.lock().unwrap();
+    let mut names: Vec<String> = Vec::new();
+    for bank in banks {
+        if let Some(l) = labels.get(&((bank << 16) | value)) {
+            if l.label_type != LabelType::Blocked && !names.contains(&l.name) {
+                names.push(l.name.to_string());
+            }
+        }
+    }
+    names
+}
+
+pub fn write_report(literals: &[Literal], filename: &str) {
+    let mut output_file = File::create(filename).unwrap();
+    let _ = writeln!(output_file, "# Literals that match a label address and would break if it moved.");
+    let _ = writeln!(output_file, "# Add an override (type: Pointer/Data, or an override on the instruction) to make them symbolic.\n");
+
+    for l in literals {
+        let _ = writeln!(output_file, "${:06X}  {:<10} ${:04X}  {}", l.address, l.kind, l.value, l.candidates.join(", "));
+    }
+}
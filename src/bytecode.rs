@@ -0,0 +1,167 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt};
+
+use crate::{config::{Config, InstructionArg, InstructionSet, Override, OverrideAddr}, label::LABELS, line::Line, structs::{self, FieldTag, Leaf}};
+
+/* Lists stop after this many entries if they never reach an instruction that ends them */
+const MAX_ENTRIES: u64 = 0x1000;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    UnknownSet { name: String },
+    UnknownInstruction { address: u64, set: String, value: u64 },
+    Misaligned { address: u64, set: String, entry: String }
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::UnknownSet { name } => write!(f, "unknown instruction set \"{}\"", name),
+            BytecodeError::UnknownInstruction { address, set, value } =>
+                write!(f, "${:06X}: ${:04X} isn't a {} instruction, the list stops here", address, value, set),
+            BytecodeError::Misaligned { address, set, entry } =>
+                write!(f, "${:06X}: {} {} doesn't line up with the data, the list stops here", address, set, entry)
+        }
+    }
+}
+
+fn list_name(set: &InstructionSet) -> String {
+    format!("{}List", set.name)
+}
+
+/* The values of an entry laid out one after the other. Goto arguments point into the list's own bank,
+   Pointer arguments with a "struct" point at a record of that struct. */
+fn leaves(set: &InstructionSet, head: Option<&str>, args: &[InstructionArg], list_bank: u64) -> Vec<Leaf> {
+    let mut leaves = Vec::new();
+    let mut offset = 0;
+    if let Some(name) = head {
+        leaves.push(Leaf { offset, length: 2, path: name.to_string(), _type: "Pointer".to_string(), db: Some(set.bank), base: None, target: None, _enum: None });
+        offset += 2;
+    }
+
+    for arg in args {
+        let (_type, db, target) = match arg._type.as_str() {
+            "Goto" => ("Pointer", Some(list_bank), Some(list_name(set))),
+            t => (t, arg.db, arg._struct.clone())
+        };
+        leaves.push(Leaf { offset, length: arg.length, path: arg.name.to_string(), _type: _type.to_string(), db, base: None, target, _enum: None });
+        offset += arg.length;
+    }
+    leaves
+}
+
+fn struct_root(addr: u64, name: &str) -> Override {
+    Override {
+        addr: OverrideAddr::Address(addr),
+        _type: Some("Struct".to_string()),
+        _struct: Some(name.to_string()),
+        ..Default::default()
+    }
+}
+
+/* Decodes the lists tagged with an "Instructions" override into tags for every value, the same as
+   struct records get, so each instruction ends up on its own line with its handler and arguments
+   written symbolically. Goto arguments are followed as lists of their own. Structs that arguments
+   point at, or that follow an instruction with a "struct", come back as Struct overrides. */
+pub fn resolve(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> (HashMap<u64, FieldTag>, Vec<Override>, Vec<BytecodeError>) {
+    let mut tags: HashMap<u64, FieldTag> = HashMap::new();
+    let mut roots: Vec<Override> = Vec::new();
+    let mut errors: Vec<BytecodeError> = Vec::new();
+
+    let mut values: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    for line in lines.values().flatten() {
+        if let Line::Data(d) = line {
+            values.extend(d.entries().into_iter().map(|(a, v)| (a, (v.size(), v.as_u64()))));
+        }
+    }
+
+    let mut pending: Vec<(u64, &InstructionSet)> = Vec::new();
+    for ov in config.overrides.iter().filter(|ov| ov._type.as_deref() == Some("Instructions")) {
+        let name = ov.set.clone().unwrap_or_default();
+        let start = match &ov.addr {
+            OverrideAddr::Range(r) => r[0],
+            OverrideAddr::Address(a) => *a
+        };
+        match config.instruction_sets.iter().find(|s| s.name == name) {
+            Some(set) => pending.push((start, set)),
+            None => errors.push(BytecodeError::UnknownSet { name })
+        }
+    }
+    pending.reverse();
+
+    let mut visited: HashSet<u64> = HashSet::new();
+    while let Some((start, set)) = pending.pop() {
+        let mut entry = start;
+        for i in 0..MAX_ENTRIES {
+            if !visited.insert(entry) {
+                break;
+            }
+
+            let value = match values.get(&entry) {
+                Some((2, v)) => *v,
+                Some(_) => {
+                    errors.push(BytecodeError::Misaligned { address: entry, set: set.name.to_string(), entry: "entry".to_string() });
+                    break;
+                },
+                None => break
+            };
+
+            let instruction = set.instructions.iter().find(|ins| ins.handler == value);
+            let entry_leaves = match instruction {
+                Some(ins) => leaves(set, Some(&ins.name), &ins.args, start >> 16),
+                None if value < 0x8000 && !set.frame.is_empty() => leaves(set, None, &set.frame, start >> 16),
+                None => {
+                    errors.push(BytecodeError::UnknownInstruction { address: entry, set: set.name.to_string(), value });
+                    break;
+                }
+            };
+
+            if let Some(leaf) = entry_leaves.iter().find(|l| values.get(&(entry + l.offset)).map(|(size, _)| *size) != Some(l.length)) {
+                errors.push(BytecodeError::Misaligned { address: entry + leaf.offset, set: set.name.to_string(), entry: leaf.path.to_string() });
+                break;
+            }
+
+            for leaf in &entry_leaves {
+                let addr = entry + leaf.offset;
+                if let Some(target) = &leaf.target {
+                    let value = values[&addr].1;
+                    let target_addr = if leaf.length < 3 { (leaf.db.unwrap_or(addr >> 16) << 16) | (value & 0xFFFF) } else { value };
+                    if *target == list_name(set) {
+                        pending.push((target_addr, set));
+                    } else if (value & 0xFFFF) >= 0x8000 {
+                        roots.push(struct_root(target_addr, target));
+                    }
+                }
+                tags.entry(addr).or_insert(FieldTag { st: list_name(set), record: entry, leaf: leaf.clone(), labelled: i == 0 });
+            }
+            entry += entry_leaves.iter().map(|l| l.length).sum::<u64>();
+
+            /* A struct right after the instruction, the list goes on after it */
+            if let Some(name) = instruction.and_then(|ins| ins._struct.as_ref()) {
+                roots.push(struct_root(entry, name));
+                match structs::layout(config, name) {
+                    Ok(layout) => entry += layout.size,
+                    Err(_) => break
+                }
+            }
+
+            if instruction.map(|ins| ins.end).unwrap_or(false) {
+                break;
+            }
+        }
+    }
+
+    (tags, roots, errors)
+}
+
+/* Handlers get the instruction's name instead of a generated one */
+pub fn name_labels(config: &Config) {
+    let mut labels = LABELS.lock().unwrap();
+    for set in &config.instruction_sets {
+        for ins in &set.instructions {
+            let addr = (set.bank << 16) | ins.handler;
+            if let Some(label) = labels.get_mut(&addr).filter(|l| l.name == format!("{}_{:04X}", ins.name, ins.handler)) {
+                label.name = format!("{}_{}", set.name, ins.name);
+            }
+        }
+    }
+}
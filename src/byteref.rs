@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use if_chain::if_chain;
+
+use crate::{code::{ArgType, Code}, config::{Config, OverrideAddr}, label::{Label, LabelType, LABELS}, line::Line, opcode::AddrMode};
+
+/* Which part of an address a value holds. Low is the low byte, or the low word for 16-bit values. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    Low,
+    High,
+    Bank
+}
+
+impl Part {
+    fn from_type(t: &str) -> Option<Part> {
+        match t {
+            "Low" => Some(Part::Low),
+            "High" => Some(Part::High),
+            "Bank" => Some(Part::Bank),
+            _ => None
+        }
+    }
+}
+
+/* A value that only holds part of the address it belongs to */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ByteRef {
+    pub target: u64,
+    pub part: Part
+}
+
+/* The expression that gives back the part of a label, for a value of the given size in bytes */
+pub fn expr(name: &str, part: Part, size: u64) -> String {
+    match (part, size) {
+        (Part::Low, 1) => format!("{}&$FF", name),
+        (Part::Low, _) => name.to_string(),
+        (Part::High, 1) => format!("({}>>8)&$FF", name),
+        (Part::High, _) => format!("{}>>8", name),
+        (Part::Bank, _) => format!("{}>>16", name)
+    }
+}
+
+fn ensure_label(labels: &mut HashMap<u64, Label>, code_addrs: &HashSet<u64>, target: u64) {
+    labels.entry(target).or_insert_with(|| {
+        let (prefix, label_type) = if code_addrs.contains(&target) { ("SUB", LabelType::Subroutine) } else { ("DAT", LabelType::Data) };
+        Label { address: target, name: format!("{}_{:06X}", prefix, target), label_type, assigned: false }
+    });
+}
+
+fn immediate(c: &Code) -> Option<u64> {
+    match (&c.opcode.addr_mode, &c.arg) {
+        (AddrMode::Immediate, ArgType::Address(a)) => Some(*a),
+        _ => None
+    }
+}
+
+/* Finds every value that holds part of an address, from the "Low", "High" and "Bank" overrides
+   and from the usual patterns in the code: a bank pushed and pulled into the data bank register,
+   and long pointers stored as a low word and a bank byte. */
+pub fn resolve(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> HashMap<u64, ByteRef> {
+    let mut refs: HashMap<u64, ByteRef> = HashMap::new();
+
+    let code: Vec<&Code> = lines.values().flatten().filter_map(|l| match l {
+        Line::Code(c) => Some(c),
+        _ => None
+    }).collect();
+    let code_addrs: HashSet<u64> = code.iter().map(|c| c.address).collect();
+
+    /* Every data value by address, along with its size */
+    let mut values: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    for line in lines.values().flatten() {
+        if let Line::Data(d) = line {
+            values.extend(d.entries().into_iter().map(|(a, v)| (a, (v.size(), v.as_u64()))));
+        }
+    }
+    let byte_at = |addr: u64| -> Option<u64> {
+        values.range(..=addr).next_back()
+            .filter(|(a, (size, _))| addr < *a + size)
+            .map(|(a, (_, v))| (v >> ((addr - a) * 8)) & 0xFF)
+    };
+
+    let mut labels = LABELS.lock().unwrap();
+    for ov in &config.overrides {
+        let part = match ov._type.as_deref().and_then(Part::from_type) {
+            Some(p) => p,
+            None => continue
+        };
+        let (start, end) = match &ov.addr {
+            OverrideAddr::Range(r) => (r[0], r[1]),
+            OverrideAddr::Address(a) => (*a, *a)
+        };
+
+        /* Instructions name the address they take a part of */
+        if let Some(target) = ov.target {
+            ensure_label(&mut labels, &code_addrs, target);
+            refs.insert(start, ByteRef { target, part });
+            continue;
+        }
+
+        /* Split tables put the pointer back together from the tables' entries */
+        if let Some(split) = &ov.split {
+            let low_words = values.get(&split.low).map(|(size, _)| *size == 2).unwrap_or(false);
+            for (addr, (size, _)) in values.range(start..=end) {
+                let i = (addr - start) / size;
+                let low = if low_words {
+                    byte_at(split.low + i * 2).zip(byte_at(split.low + i * 2 + 1)).map(|(lo, hi)| (hi << 8) | lo)
+                } else {
+                    byte_at(split.low + i).zip(split.high.and_then(|h| byte_at(h + i))).map(|(lo, hi)| (hi << 8) | lo)
+                };
+                let bank = match split.bank {
+                    Some(b) => byte_at(b + i),
+                    None => Some(ov.db.unwrap_or(split.low >> 16))
+                };
+
+                if let Some((low, bank)) = low.zip(bank) {
+                    let target = (bank << 16) | low;
+                    ensure_label(&mut labels, &code_addrs, target);
+                    refs.insert(*addr, ByteRef { target, part });
+                }
+            }
+        }
+    }
+    drop(labels);
+
+    for (i, c) in code.iter().enumerate() {
+        if refs.contains_key(&c.address) {
+            continue;
+        }
+
+        /* LDA #bank : PHA : PLB, the label is whatever the code goes on to use with that bank */
+        if_chain! {
+            if let Some(value) = immediate(c);
+            if matches!(c.opcode.name, "LDA" | "LDX" | "LDY") && value <= 0xFF;
+            if let Some(push) = code.get(i + 1);
+            if matches!((c.opcode.name, push.opcode.name), ("LDA", "PHA") | ("LDX", "PHX") | ("LDY", "PHY"));
+            if code[i + 2..].iter().take(2).any(|n| n.opcode.name == "PLB");
+            then {
+                let target = code[i + 2..].iter()
+                    .skip_while(|n| n.opcode.name == "PLB")
+                    .take_while(|n| !matches!(n.opcode.name, "RTS" | "RTL" | "RTI" | "PLB"))
+                    .take(40)
+                    .filter(|n| n.uses_data_bank() && n.db as u64 == value)
+                    .find_map(|n| n.operand_label(config).filter(|(a, offset)| *offset == 0 && a >> 16 == value).map(|(a, _)| a))
+                    .or_else(|| LABELS.lock().unwrap().values()
+                        .filter(|l| l.address >> 16 == value && (l.address & 0xFFFF) >= 0x8000 && l.label_type != LabelType::Blocked)
+                        .map(|l| l.address)
+                        .min());
+
+                if let Some(target) = target {
+                    refs.insert(c.address, ByteRef { target, part: Part::Bank });
+                }
+                continue;
+            }
+        }
+
+        /* LDA #low : STA ptr and LDA #bank : STA ptr+2 close to each other make a long pointer */
+        if_chain! {
+            if c.opcode.name == "LDA" && c.length == 2;
+            if let Some(low) = immediate(c);
+            if let Some(store) = code.get(i + 1).filter(|s| s.opcode.name == "STA");
+            if let ArgType::Address(ptr) = store.arg;
+            then {
+                let bank_load = code[i.saturating_sub(6)..(i + 8).min(code.len())].windows(2).find(|w| {
+                    w[0].opcode.name == "LDA" && w[1].opcode.name == "STA" &&
+                        w[1].opcode.addr_mode == store.opcode.addr_mode &&
+                        matches!(w[1].arg, ArgType::Address(a) if a == ptr + 2) &&
+                        immediate(w[0]).map(|b| b <= 0xFF).unwrap_or(false)
+                });
+
+                if let Some(w) = bank_load {
+                    let target = (immediate(w[0]).unwrap() << 16) | low;
+                    if LABELS.lock().unwrap().get(&target).map(|l| l.label_type != LabelType::Blocked).unwrap_or(false) && !refs.contains_key(&w[0].address) {
+                        refs.insert(c.address, ByteRef { target, part: Part::Low });
+                        refs.insert(w[0].address, ByteRef { target, part: Part::Bank });
+                    }
+                }
+            }
+        }
+    }
+
+    refs
+}
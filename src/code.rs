@@ -1,4 +1,4 @@
-use crate::{config::Config, label::{self, LabelType}, opcode::{Opcode, AddrMode}};
+use crate::{byteref, config::Config, constants, label::{self, LabelType}, opcode::{Opcode, AddrMode}};
 
 #[derive(Debug, Clone)]
 pub enum ArgType {
@@ -15,86 +15,170 @@ pub struct Code {
     pub comment: Option<String>,
     pub length: u8,
     pub db: u8,
+    pub dp: Option<u16>,
+    pub log_db: Option<u8>,
+    pub effective: Option<u64>,
+    pub acc_8bit: Option<bool>,
+    pub index_8bit: Option<bool>,
 }
 
 impl Code {
-    fn arg_label(&self, config: &Config) -> String {
-        /* TODO: Get label if exists */
+    /* Resolves a direct page operand through the tracked D register, None if D isn't known */
+    pub fn direct_page_addr(&self) -> Option<u64> {
+        match (&self.arg, self.dp) {
+            (ArgType::Address(addr), Some(dp)) if self.opcode.addr_mode.is_direct() => {
+                let addr = (dp as u64 + (addr & 0xFF)) & 0xFFFF;
+                Some(if addr < 0x2000 { 0x7E0000 | addr } else { addr })
+            },
+            _ => None
+        }
+    }
+
+    /* Whether the operand is resolved through the data bank register */
+    pub fn uses_data_bank(&self) -> bool {
+        match self.opcode.addr_mode {
+            AddrMode::Absolute => !matches!(self.opcode.name, "JMP" | "JSR" | "PEA"),
+            AddrMode::AbsoluteIndexedX | AddrMode::AbsoluteIndexedY |
+            AddrMode::DirectIndirect | AddrMode::DirectIndexedIndirect | AddrMode::DirectIndirectIndexed => self.opcode.name != "PEI",
+            _ => false
+        }
+    }
+
+    /* The instruction as it sits in the ROM */
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode.opcode];
+        match self.arg {
+            ArgType::Address(a) => bytes.extend((0..self.length).map(|i| ((a >> (i * 8)) & 0xFF) as u8)),
+            ArgType::BlockMove(dst, src) => bytes.extend([dst, src]),
+            ArgType::None => ()
+        }
+        bytes
+    }
+
+    /* Where a branch, jump or call goes, None for indirect jumps and everything else */
+    pub fn branch_target(&self) -> Option<u64> {
+        match (&self.arg, &self.opcode.addr_mode) {
+            (ArgType::Address(a), AddrMode::Relative) => Some(((self.address as i64) + 2 + (a & 0xFF) as i8 as i64) as u64),
+            (ArgType::Address(a), AddrMode::RelativeLong) if self.opcode.name == "BRL" => Some(((self.address as i64) + 3 + (a & 0xFFFF) as i16 as i64) as u64),
+            (ArgType::Address(a), AddrMode::Absolute) if matches!(self.opcode.name, "JMP" | "JSR") => Some((self.address & 0xFF0000) | (a & 0xFFFF)),
+            (ArgType::Address(a), AddrMode::AbsoluteLong) if matches!(self.opcode.name, "JML" | "JSL") => Some(*a),
+            _ => None
+        }
+    }
+
+    /* Bank used to resolve a 16-bit operand, jumps and PEA stay in the program bank */
+    pub fn operand_bank(&self) -> u8 {
+        match self.opcode.addr_mode {
+            AddrMode::Absolute | AddrMode::AbsoluteIndexedIndirect if !self.uses_data_bank() => (self.address >> 16) as u8,
+            _ => self.db
+        }
+    }
+
+    /* The address an operand refers to, before any label matching */
+    pub fn operand_addr(&self, config: &Config) -> Option<u64> {
         /* Make sure to handle PC-relative addresses correctly */
         match self.arg {
-            ArgType::Address(addr) => {
-                let label_addr = match self.opcode.addr_mode {
-                    AddrMode::Relative => {
-                        ((self.address as i64) + 2 + (((addr & 0xFF) as i8)) as i64) as u64
-                    },
-                    AddrMode::RelativeLong => {
-                        ((self.address as i64) + 2 + (((addr & 0xFFFF) as i16)) as i64) as u64
-                    },
-                    _ => {
-                        match self.length {
-                            1 => 0x7E0000 | (addr & 0xFF),
-                            2 => match addr {
-                                0..=0x1FFF => 0x7E0000 | (addr & 0xFFFF),
-                                0x2000..=0x7FFF => (addr & 0xFFFF),
-                                _ => ((self.db as u64) << 16) | (addr & 0xFFFF)
+            ArgType::Address(addr) => Some(match self.opcode.addr_mode {
+                AddrMode::Relative => {
+                    ((self.address as i64) + 2 + ((addr & 0xFF) as i8) as i64) as u64
+                },
+                AddrMode::RelativeLong => {
+                    ((self.address as i64) + 3 + ((addr & 0xFFFF) as i16) as i64) as u64
+                },
+                _ => {
+                    match self.length {
+                        1 if self.opcode.addr_mode.is_direct() => self.direct_page_addr()?,
+                        1 => 0x7E0000 | (addr & 0xFF),
+                        2 => match addr {
+                            0..=0x1FFF => 0x7E0000 | (addr & 0xFFFF),
+                            0x2000..=0x7FFF => addr & 0xFFFF,
+                            _ if self.opcode.addr_mode == AddrMode::Immediate => {
+                                let db = config.get_override(self.address).and_then(|o| o.db).unwrap_or(self.db as u64);
+                                (db << 16) | (addr & 0xFFFF)
                             },
-                            3 => addr,
-                            _ => panic!("Invalid argument length")
-                        }
+                            _ => ((self.operand_bank() as u64) << 16) | (addr & 0xFFFF)
+                        },
+                        3 => addr,
+                        _ => panic!("Invalid argument length")
                     }
-                };
+                }
+            }),
+            _ => None
+        }
+    }
 
-                let labels = label::LABELS.lock().unwrap();
+    /* The label an operand is written with and its offset from the operand (-1 means Label+1) */
+    pub fn operand_label(&self, config: &Config) -> Option<(u64, i64)> {
+        if let Some(r) = config.byte_refs.get(&self.address) {
+            return Some((r.target, 0)).filter(|(a, _)| label::LABELS.lock().unwrap().contains_key(a));
+        }
 
-                let label = {
-                    if labels.contains_key(&label_addr) {
-                        (Some(&labels[&label_addr]), 0)
-                    } else if self.opcode.addr_mode != AddrMode::Relative &&
-                                self.opcode.addr_mode != AddrMode::RelativeLong &&
-                                self.opcode.name != "JSR" &&
-                                self.opcode.name != "JSL"
-                        {
-                        if labels.contains_key(&(label_addr - 1)) {
-                            (Some(&labels[&(label_addr - 1)]), -1)
-                        } else if labels.contains_key(&(label_addr + 1)) {
-                            (Some(&labels[&(label_addr + 1)]), 1)
-                        } else if labels.contains_key(&(label_addr - 2)) {
-                            (Some(&labels[&(label_addr - 2)]), -2)
-                        } else if labels.contains_key(&(label_addr + 2)) {
-                            (Some(&labels[&(label_addr + 2)]), 2)
-                        } else {
-                            (None, 0)                         
-                        }
-                    } else {
-                        (None, 0)    
-                    }
-                };
-
-                match label {
-                    (Some(l), offset) => {
-                        if (((self.opcode.addr_mode == AddrMode::Immediate || self.opcode.addr_mode == AddrMode::ImmediateByte) && config.get_override(self.address).is_some()) || (self.opcode.addr_mode != AddrMode::Immediate && self.opcode.addr_mode != AddrMode::ImmediateByte)) && l.label_type != LabelType::Blocked {
-                            match offset {
-                                0 => l.name.to_string(),
-                                -1 | -2 => format!("{}+{}", l.name, -offset),
-                                1 | 2 => format!("{}{}", l.name, -offset),
-                                _ => panic!("Invalid argument length")
-                            }
-                        } else {
-                            match self.length {
-                                1 => format!("${:02X}", addr),
-                                2 => format!("${:04X}", addr),
-                                3 => format!("${:06X}", addr),
-                                _ => panic!("Invalid argument length")
-                            }                                
+        let label_addr = self.operand_addr(config)?;
+
+        if (self.opcode.addr_mode.is_direct() && self.dp.is_none()) ||
+            self.opcode.addr_mode == AddrMode::StackRelative ||
+            self.opcode.addr_mode == AddrMode::StackRelativeIndirectIndexed
+        {
+            return None;
+        }
+
+        let labels = label::LABELS.lock().unwrap();
+
+        let label = {
+            if labels.contains_key(&label_addr) {
+                Some((label_addr, 0))
+            } else if self.opcode.addr_mode != AddrMode::Relative &&
+                        self.opcode.addr_mode != AddrMode::RelativeLong &&
+                        self.opcode.name != "JSR" &&
+                        self.opcode.name != "JSL"
+                {
+                [-1_i64, 1, -2, 2].iter()
+                    .map(|o| ((label_addr as i64 + o) as u64, *o))
+                    .find(|(a, _)| labels.contains_key(a))
+            } else {
+                None
+            }
+        };
+
+        let immediate = self.opcode.addr_mode == AddrMode::Immediate || self.opcode.addr_mode == AddrMode::ImmediateByte;
+        label.filter(|(a, _)| (!immediate || config.get_override(self.address).is_some()) && labels[a].label_type != LabelType::Blocked)
+    }
+
+    fn arg_label(&self, config: &Config) -> String {
+        match self.arg {
+            ArgType::Address(addr) => {
+                if let Some(constant) = config.immediate_enums.get(&self.address).and_then(|e| constants::expr(config, e, addr)) {
+                    return constant;
+                }
+
+                match self.operand_label(config) {
+                    Some((label_addr, _)) if config.byte_refs.contains_key(&self.address) => {
+                        let labels = label::LABELS.lock().unwrap();
+                        byteref::expr(&labels[&label_addr].name, config.byte_refs[&self.address].part, self.length as u64)
+                    },
+                    Some((label_addr, offset)) => {
+                        let labels = label::LABELS.lock().unwrap();
+                        let l = &labels[&label_addr];
+                        let name = match offset {
+                            0 => l.name.to_string(),
+                            -1 | -2 => format!("{}+{}", l.name, -offset),
+                            1 | 2 => format!("{}{}", l.name, -offset),
+                            _ => panic!("Invalid argument length")
+                        };
+
+                        /* The assembler only keeps the low byte, so take the direct page back out */
+                        match self.dp {
+                            Some(dp) if dp != 0 && self.opcode.addr_mode.is_direct() => format!("{}-${:04X}", name, dp),
+                            _ => name
                         }
                     },
-                    (None, _) => {
+                    None => {
                         match self.length {
                             1 => format!("${:02X}", addr),
                             2 => format!("${:04X}", addr),
                             3 => format!("${:06X}", addr),
                             _ => panic!("Invalid argument length")
-                        }                        
+                        }
                     }
                 }
             },
@@ -107,6 +191,41 @@ impl Code {
 }
 
 impl Code {
+    /* Shows where indexed and indirect operands ended up according to the log */
+    fn target_comment(&self) -> String {
+        let base = match (&self.arg, self.length) {
+            (ArgType::Address(addr), 2) => match addr {
+                0..=0x1FFF => 0x7E0000 | addr,
+                0x2000..=0x7FFF => *addr,
+                _ => ((self.db as u64) << 16) | addr
+            },
+            (ArgType::Address(addr), 3) => *addr,
+            _ => match self.direct_page_addr() {
+                Some(a) => a,
+                /* Without the direct page there's nothing to say where the operand is */
+                None => return "".to_string()
+            }
+        };
+
+        /* Indexing with a zero index lands on the operand itself, nothing to add then */
+        let shown = match self.opcode.addr_mode {
+            AddrMode::AbsoluteIndexedX | AddrMode::AbsoluteIndexedY | AddrMode::AbsoluteIndexedLong |
+            AddrMode::DirectIndexedX | AddrMode::DirectIndexedY => self.effective != Some(base),
+            ref mode => mode.is_indirect()
+        };
+
+        match self.effective {
+            Some(target) if shown => {
+                let labels = label::LABELS.lock().unwrap();
+                match labels.get(&target) {
+                    Some(l) if l.label_type != LabelType::Blocked => format!("[{}] ", l.name),
+                    _ => format!("[${:02X}:{:04X}] ", target >> 16, target & 0xFFFF)
+                }
+            },
+            _ => "".to_string()
+        }
+    }
+
     pub fn to_string(&self, config: &Config) -> String {
         let opcode = match self.opcode.addr_mode {
             AddrMode::Absolute =>                       format!("{}.w {}", self.opcode.name, self.arg_label(config)),
@@ -135,6 +254,6 @@ impl Code {
             AddrMode::StackRelativeIndirectIndexed =>   format!("{}.b ({},S),Y", self.opcode.name, self.arg_label(config)),
         };
 
-        format!("    {:<40};| {:06X} | {:02X} | {}", opcode, self.address, self.db, self.comment.as_ref().unwrap_or(&"".to_owned()))
+        format!("    {:<40};| {:06X} | {:02X} | {}{}", opcode, self.address, self.db, self.target_comment(), self.comment.as_ref().unwrap_or(&"".to_owned()))
     }
 }
\ No newline at end of file
@@ -0,0 +1,389 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, fs::File, io::Write};
+
+use crate::{config::{Config, OverrideAddr}, incbin::{self, Binary}, line::Line};
+
+/* The longest a single command can be, with the two byte header */
+const MAX_LENGTH: usize = 0x400;
+/* Compressed data has no size of its own, this is as far as it's read looking for the end */
+const MAX_COMPRESSED: u64 = 0x10000;
+/* How many earlier places with the same bytes get tried for a dictionary copy */
+const MAX_CANDIDATES: usize = 0x100;
+
+#[derive(Debug)]
+pub enum CompressionError {
+    Truncated { offset: usize },
+    BadReference { offset: usize, source: usize },
+    LocalLabel
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionError::Truncated { offset } => write!(f, "data ends at +${:X} before the end marker", offset),
+            CompressionError::BadReference { offset, source } =>
+                write!(f, "copy at +${:X} reads from +${:X}, past what's been decompressed", offset, source),
+            CompressionError::LocalLabel => write!(f, "has a local label inside, left as it is")
+        }
+    }
+}
+
+/* Decompresses the format the game's decompression routines read, giving back the data and how many
+   compressed bytes it took. Every command starts with a CCCLLLLL header byte for length L+1, or
+   111CCCLL LLLLLLLL for longer ones, and $FF ends the data:
+     0: L+1 bytes copied as they are
+     1: one byte repeated
+     2: two bytes repeated one after the other
+     3: one byte, going up by one each time
+     4: copy from a two byte offset into what's been decompressed so far, 5 the same inverted
+     6: copy from a one byte distance back from the end of it, 7 the same inverted */
+pub fn decompress(input: &[u8]) -> Result<(Vec<u8>, usize), CompressionError> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    let byte = |pos: usize| input.get(pos).copied().ok_or(CompressionError::Truncated { offset: pos });
+
+    loop {
+        let header = byte(pos)?;
+        pos += 1;
+        if header == 0xFF {
+            return Ok((output, pos));
+        }
+
+        let (command, length) = if header >> 5 == 7 {
+            let low = byte(pos)?;
+            pos += 1;
+            ((header >> 2) & 7, ((((header & 3) as usize) << 8) | low as usize) + 1)
+        } else {
+            (header >> 5, (header & 0x1F) as usize + 1)
+        };
+
+        match command {
+            0 => {
+                for _ in 0..length {
+                    output.push(byte(pos)?);
+                    pos += 1;
+                }
+            },
+            1 => {
+                let value = byte(pos)?;
+                pos += 1;
+                output.extend(std::iter::repeat_n(value, length));
+            },
+            2 => {
+                let values = [byte(pos)?, byte(pos + 1)?];
+                pos += 2;
+                output.extend((0..length).map(|i| values[i & 1]));
+            },
+            3 => {
+                let value = byte(pos)?;
+                pos += 1;
+                output.extend((0..length).map(|i| value.wrapping_add(i as u8)));
+            },
+            _ => {
+                let source = if command < 6 {
+                    let source = (byte(pos)? as usize) | ((byte(pos + 1)? as usize) << 8);
+                    pos += 2;
+                    source
+                } else {
+                    let distance = byte(pos)? as usize;
+                    pos += 1;
+                    output.len().wrapping_sub(distance)
+                };
+                let invert = if command & 1 == 1 { 0xFF } else { 0 };
+                if source >= output.len() {
+                    return Err(CompressionError::BadReference { offset: pos, source });
+                }
+                /* Copies can run into what they're writing, so it goes byte by byte */
+                for i in 0..length {
+                    output.push(output[source + i] ^ invert);
+                }
+            }
+        }
+    }
+}
+
+/* Command 7 only has the long header, its short one is where the long ones start */
+fn header(output: &mut Vec<u8>, command: u8, length: usize) {
+    if header_size(command, length) == 2 {
+        output.push(0xE0 | (command << 2) | ((length - 1) >> 8) as u8);
+        output.push(((length - 1) & 0xFF) as u8);
+    } else {
+        output.push((command << 5) | (length - 1) as u8);
+    }
+}
+
+fn header_size(command: u8, length: usize) -> usize {
+    if length > 32 || command == 7 { 2 } else { 1 }
+}
+
+fn flush_literals(output: &mut Vec<u8>, literals: &mut Vec<u8>) {
+    for chunk in literals.chunks(MAX_LENGTH) {
+        header(output, 0, chunk.len());
+        output.extend(chunk);
+    }
+    literals.clear();
+}
+
+/* How long the data at "pos" keeps matching the data at "source", inverted or not */
+fn match_length(input: &[u8], source: usize, pos: usize, invert: u8) -> usize {
+    (0..(input.len() - pos).min(MAX_LENGTH))
+        .take_while(|i| input[source + i] ^ invert == input[pos + i])
+        .count()
+}
+
+/* Compresses data so the game can decompress it. At every position the command that saves the most
+   is taken, bytes no command saves anything on are copied as they are. This isn't how the original
+   data was made, so it doesn't always come out the same. */
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut literals: Vec<u8> = Vec::new();
+    let mut seen: HashMap<[u8; 2], Vec<usize>> = HashMap::new();
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let limit = (input.len() - pos).min(MAX_LENGTH);
+        let first = input[pos];
+
+        /* Candidates as (command, length, argument bytes) */
+        let mut candidates: Vec<(u8, usize, Vec<u8>)> = Vec::new();
+        let run = (0..limit).take_while(|i| input[pos + i] == first).count();
+        candidates.push((1, run, vec![first]));
+        if limit >= 2 {
+            let second = input[pos + 1];
+            let run = (0..limit).take_while(|i| input[pos + i] == if i & 1 == 0 { first } else { second }).count();
+            candidates.push((2, run, vec![first, second]));
+        }
+        let run = (0..limit).take_while(|i| input[pos + i] == first.wrapping_add(*i as u8)).count();
+        candidates.push((3, run, vec![first]));
+
+        for (command, invert) in [(4, 0), (5, 0xFF)] {
+            if pos + 1 < input.len() {
+                let key = [input[pos] ^ invert, input[pos + 1] ^ invert];
+                if let Some(sources) = seen.get(&key) {
+                    if let Some((source, length)) = sources.iter().rev().take(MAX_CANDIDATES)
+                        .map(|s| (*s, match_length(input, *s, pos, invert)))
+                        .max_by_key(|(s, length)| (*length, *s)) {
+                        let near = pos - source <= 0xFF;
+                        candidates.push(if near {
+                            /* A long inverted one would have the $FF end marker for its header */
+                            (command + 2, if command == 5 { length.min(0x300) } else { length }, vec![(pos - source) as u8])
+                        } else {
+                            (command, length, vec![(source & 0xFF) as u8, (source >> 8) as u8])
+                        });
+                    }
+                }
+            }
+        }
+
+        /* Whatever saves the most over copying the bytes as they are, ties going to the lower command */
+        let best = candidates.into_iter()
+            .filter(|(command, length, args)| *length > header_size(*command, *length) + args.len())
+            .max_by_key(|(command, length, args)| (length - header_size(*command, *length) - args.len(), std::cmp::Reverse(*command)));
+
+        let length = match best {
+            Some((command, length, args)) => {
+                flush_literals(&mut output, &mut literals);
+                header(&mut output, command, length);
+                output.extend(args);
+                length
+            },
+            None => {
+                literals.push(first);
+                1
+            }
+        };
+
+        for p in pos..pos + length {
+            if p + 1 < input.len() && p <= 0xFFFF {
+                seen.entry([input[p], input[p + 1]]).or_default().push(p);
+            }
+        }
+        pos += length;
+    }
+
+    flush_literals(&mut output, &mut literals);
+    output.push(0xFF);
+    output
+}
+
+/* How a compressed region came out of the round trip */
+pub struct Region {
+    pub address: u64,
+    pub name: String,
+    pub result: Result<(usize, usize, Vec<u8>, Vec<u8>), CompressionError>
+}
+
+/* Decompresses the regions with a "Compressed" override into "<folder>/<label>.bin" for editing. The
+   compressed bytes go to "<label>.lz" next to it, which is what the bank files include. Each region is
+   compressed again to check it comes out the same, the "compress" command rebuilds the edited ones. */
+pub fn extract(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config, folder: &str) -> Vec<Region> {
+    let mut regions = Vec::new();
+    for ov in config.overrides.iter().filter(|ov| ov._type.as_deref() == Some("Compressed")) {
+        let (start, limit) = match &ov.addr {
+            OverrideAddr::Range(r) => (r[0], r[1] - r[0] + 1),
+            OverrideAddr::Address(a) => (*a, MAX_COMPRESSED)
+        };
+
+        let bytes = incbin::read_bytes(lines, start, limit);
+        let (data, used) = match decompress(&bytes) {
+            Ok(d) => d,
+            Err(e) => {
+                regions.push(Region { address: start, name: String::new(), result: Err(e) });
+                continue;
+            }
+        };
+        let end = (0..used).fold(start, |a, _| incbin::next_address(a));
+        let (base, inner) = match incbin::region_labels(start, end, "LZ") {
+            Some(l) => l,
+            None => {
+                regions.push(Region { address: start, name: String::new(), result: Err(CompressionError::LocalLabel) });
+                continue;
+            }
+        };
+
+        std::fs::create_dir_all(folder).unwrap();
+        let name = base.trim_start_matches('.').to_string();
+        let mut output_file = File::create(format!("{}/{}.bin", folder, name)).unwrap();
+        let _ = output_file.write_all(&data);
+        output_file = File::create(format!("{}/{}.lz", folder, name)).unwrap();
+        let _ = output_file.write_all(&bytes[..used]);
+
+        incbin::replace(lines, start, end, vec![Line::Binary(Binary { directive: "incbin", base, file: format!("data/{}.lz", name), labels: inner })]);
+        let original = bytes[..used].to_vec();
+        regions.push(Region { address: start, name, result: Ok((used, data.len(), compress(&data), original)) });
+    }
+    regions
+}
+
+/* Where two compressed versions part ways, with the sizes if they do */
+fn difference(original: &[u8], recompressed: &[u8]) -> String {
+    let position = original.iter().zip(recompressed).position(|(a, b)| a != b)
+        .unwrap_or(original.len().min(recompressed.len()));
+    if original == recompressed {
+        "recompresses the same".to_string()
+    } else if original.len() == recompressed.len() {
+        format!("recompresses to the same size, differing from +${:X}", position)
+    } else {
+        format!("recompresses to ${:X} bytes instead of ${:X}, differing from +${:X}", recompressed.len(), original.len(), position)
+    }
+}
+
+pub fn write_report(regions: &[Region], filename: &str) {
+    let mut report_file = File::create(filename).unwrap();
+    for r in regions {
+        let _ = match &r.result {
+            Ok((used, size, recompressed, original)) => writeln!(report_file, "${:06X} {}: ${:X} bytes, ${:X} decompressed, {}",
+                r.address, r.name, used, size, difference(original, recompressed)),
+            Err(e) => writeln!(report_file, "${:06X}: {}", r.address, e)
+        };
+    }
+}
+
+/* The build step: every "<label>.lz" in the folder with a "<label>.bin" that no longer matches it gets
+   compressed again. Unchanged ones are left as they are so the ROM stays the same. */
+pub fn rebuild(folder: &str, filename: &str) {
+    let mut report_file = File::create(filename).unwrap();
+    let mut files: Vec<_> = glob::glob(&format!("{}/*.lz", folder)).unwrap().flatten().collect();
+    files.sort();
+    for lz_path in files {
+        let bin_path = lz_path.with_extension("bin");
+        let (Ok(compressed), Ok(data)) = (std::fs::read(&lz_path), std::fs::read(&bin_path)) else {
+            continue;
+        };
+        let name = lz_path.file_stem().unwrap().to_string_lossy().to_string();
+
+        let recompressed = compress(&data);
+        let unchanged = decompress(&compressed).map(|(d, _)| d == data).unwrap_or(false);
+        if unchanged {
+            let _ = writeln!(report_file, "{}: unchanged, {}", name, difference(&compressed, &recompressed));
+        } else {
+            let _ = writeln!(report_file, "{}: rebuilt, ${:X} bytes (was ${:X})", name, recompressed.len(), compressed.len());
+            let mut output_file = File::create(&lz_path).unwrap();
+            let _ = output_file.write_all(&recompressed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) {
+        let compressed = compress(data);
+        let (decompressed, used) = decompress(&compressed).unwrap();
+        assert_eq!(decompressed, data);
+        assert_eq!(used, compressed.len());
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(decompress(&[0x02, 0x11, 0x22, 0x33, 0xFF]).unwrap(), (vec![0x11, 0x22, 0x33], 5));
+    }
+
+    #[test]
+    fn fills() {
+        assert_eq!(decompress(&[0x23, 0xAA, 0xFF]).unwrap().0, vec![0xAA; 4]);
+        assert_eq!(decompress(&[0x44, 0x01, 0x02, 0xFF]).unwrap().0, vec![0x01, 0x02, 0x01, 0x02, 0x01]);
+        assert_eq!(decompress(&[0x62, 0xFE, 0xFF]).unwrap().0, vec![0xFE, 0xFF, 0x00]);
+    }
+
+    #[test]
+    fn dictionary_copies() {
+        assert_eq!(decompress(&[0x03, 1, 2, 3, 4, 0x82, 0x01, 0x00, 0xFF]).unwrap().0, vec![1, 2, 3, 4, 2, 3, 4]);
+        assert_eq!(decompress(&[0x03, 1, 2, 3, 4, 0xA1, 0x00, 0x00, 0xFF]).unwrap().0, vec![1, 2, 3, 4, 0xFE, 0xFD]);
+    }
+
+    #[test]
+    fn overlapping_copies() {
+        /* Command 6 reading what it's writing repeats the last two bytes */
+        assert_eq!(decompress(&[0x01, 0x0A, 0x0B, 0xC4, 0x02, 0xFF]).unwrap().0, vec![0x0A, 0x0B, 0x0A, 0x0B, 0x0A, 0x0B, 0x0A]);
+        /* Command 7 only has the long header, inverting what it just inverted */
+        assert_eq!(decompress(&[0x00, 0x0F, 0xFC, 0x02, 0x01, 0xFF]).unwrap().0, vec![0x0F, 0xF0, 0x0F, 0xF0]);
+    }
+
+    #[test]
+    fn long_header() {
+        let mut input = vec![0xE0, 0x20];
+        input.extend(0..0x21);
+        input.push(0xFF);
+        assert_eq!(decompress(&input).unwrap().0, (0..0x21).collect::<Vec<u8>>());
+        assert_eq!(decompress(&[0xE7, 0xFF, 0x55, 0xFF]).unwrap().0, vec![0x55; 0x400]);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(decompress(&[0x02, 0x11]), Err(CompressionError::Truncated { .. })));
+        assert!(matches!(decompress(&[0x81, 0x05, 0x00, 0xFF]), Err(CompressionError::BadReference { .. })));
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&[]);
+        round_trip(&[0x42]);
+        round_trip(&vec![0x00; 0x1000]);
+        round_trip(&(0..0x800).map(|i| (i / 3) as u8).collect::<Vec<u8>>());
+
+        /* Something like tiles: noise with repeats of earlier parts, some of them inverted */
+        let mut seed: u32 = 1;
+        let mut data: Vec<u8> = Vec::new();
+        while data.len() < 0x3000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let start = (seed >> 8) as usize % (data.len() + 1);
+            let length = (seed >> 20) as usize % 0x40;
+            match seed % 4 {
+                0 => data.push((seed >> 16) as u8),
+                1 if start + length <= data.len() => data.extend(data[start..start + length].to_vec()),
+                2 if start + length <= data.len() => data.extend(data[start..start + length].iter().map(|b| !b).collect::<Vec<u8>>()),
+                _ => data.extend(std::iter::repeat_n((seed >> 12) as u8, length))
+            }
+        }
+        round_trip(&data);
+    }
+
+    #[test]
+    fn long_inverted_copy() {
+        /* A near inverted copy longer than $300 would need the $FF end marker as its header */
+        let pattern: Vec<u8> = (0..0x10).map(|i| (i * 37 + 5) as u8).collect();
+        let data: Vec<u8> = (0..0x400).map(|i| if (i / 0x10) % 2 == 0 { pattern[i % 0x10] } else { !pattern[i % 0x10] }).collect();
+        round_trip(&data);
+    }
+}
@@ -1,20 +1,85 @@
-use serde::{Deserialize};
+use std::collections::HashMap;
+use serde::{Deserialize, de::DeserializeOwned};
 use glob::glob;
 
+use crate::{byteref::ByteRef, structs::FieldTag};
+
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct StructField {
     pub name: String,
     pub offset: u64,
+    #[serde(default)]
     pub length: u64,
     #[serde(rename = "type")]
     pub _type: String,
-    pub db: Option<u64>
+    pub db: Option<u64>,
+    pub base: Option<u64>,
+    #[serde(rename = "struct")]
+    pub _struct: Option<String>,
+    pub count: Option<u64>,
+    pub target: Option<String>,
+    #[serde(rename = "enum")]
+    pub _enum: Option<String>
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct Struct {
     pub name: String,
-    pub fields: Vec<StructField>
+    pub fields: Vec<StructField>,
+    pub terminator: Option<u64>,
+    pub until_below: Option<u64>,
+    pub last: Option<u64>,
+    pub list: Option<String>
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ConstantValue {
+    pub name: String,
+    pub value: u64
+}
+
+/* A named set of values, either an Enum where a value is one of them or Flags that get or'ed together */
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ConstantSet {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub _type: String,
+    pub values: Vec<ConstantValue>
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct InstructionArg {
+    pub name: String,
+    pub length: u64,
+    #[serde(rename = "type")]
+    pub _type: String,
+    pub db: Option<u64>,
+    #[serde(rename = "struct")]
+    pub _struct: Option<String>
+}
+
+/* A bytecode instruction, the handler word followed by its arguments */
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Instruction {
+    pub handler: u64,
+    pub name: String,
+    #[serde(default)]
+    pub args: Vec<InstructionArg>,
+    #[serde(default)]
+    pub end: bool,
+    #[serde(rename = "struct")]
+    pub _struct: Option<String>
+}
+
+/* Lists of handler pointers and their arguments. Entries that aren't a handler are a "frame" if
+   the set has one (like a timer and something to draw), otherwise they end the list. */
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct InstructionSet {
+    pub name: String,
+    pub bank: u64,
+    #[serde(default)]
+    pub frame: Vec<InstructionArg>,
+    pub instructions: Vec<Instruction>
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -23,7 +88,10 @@ pub struct Label {
     pub name: String,
     #[serde(rename = "type")]
     pub label_type: Option<String>,
-    pub length: Option<u64>
+    pub length: Option<u64>,
+    #[serde(rename = "enum")]
+    pub _enum: Option<String>,
+    pub inline: Option<String>
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -33,7 +101,21 @@ pub enum OverrideAddr {
     Range(Vec<u64>)
 }
 
+impl Default for OverrideAddr {
+    fn default() -> Self {
+        OverrideAddr::Address(0)
+    }
+}
+
+/* Start addresses of the tables that hold the parts of split pointers */
 #[derive(Debug, PartialEq, Deserialize)]
+pub struct SplitTable {
+    pub low: u64,
+    pub high: Option<u64>,
+    pub bank: Option<u64>
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
 pub struct Override {
     pub addr: OverrideAddr,
     pub db: Option<u64>,
@@ -42,31 +124,78 @@ pub struct Override {
     #[serde(rename ="struct")]
     pub _struct: Option<String>,
     pub opcode: Option<Vec<u64>>,
+    pub dp: Option<u64>,
+    pub target: Option<u64>,
+    pub split: Option<SplitTable>,
+    pub base: Option<u64>,
+    pub set: Option<String>,
+    pub palette: Option<String>,
+    pub code: Option<Vec<[u64; 2]>>,
+}
+
+impl Override {
+    pub fn contains(&self, addr: u64) -> bool {
+        match &self.addr {
+            OverrideAddr::Address(a) => *a == addr,
+            OverrideAddr::Range(r) => addr >= r[0] && addr <= r[1]
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct Options {
+    pub xref_comments: bool,
+    pub disabled_packs: Vec<String>,
+    pub incbin_threshold: u64
+}
+
+/* A set of config folders shipped together under "packs", with a version so output can say which one it was made with */
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Pack {
+    pub name: String,
+    pub version: String
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Config {
     pub labels: Vec<Label>,
     pub overrides: Vec<Override>,
-    pub structs: Vec<Struct>
+    pub structs: Vec<Struct>,
+    pub constants: Vec<ConstantSet>,
+    pub instruction_sets: Vec<InstructionSet>,
+    pub packs: Vec<Pack>,
+    pub options: Options,
+    pub byte_refs: HashMap<u64, ByteRef>,
+    pub struct_tags: HashMap<u64, FieldTag>,
+    pub immediate_enums: HashMap<u64, String>
 }
 
 impl Config {
     pub fn load(path: &str) -> Config {
-        let label_filenames = glob(&format!("{}/labels/*.yaml", path)).unwrap();        
-        let labels: Vec<Label> = label_filenames.flatten()
-            .map(|f| serde_yaml::from_str::<Vec<Label>>(&std::fs::read_to_string(f).unwrap()).unwrap())
-            .flatten().collect();
+        let options: Options = match std::fs::read_to_string(format!("{}/options.yaml", path)) {
+            Ok(s) => serde_yaml::from_str(&s).unwrap(),
+            Err(_) => Options::default()
+        };
 
-        let override_filenames = glob(&format!("{}/overrides/*.yaml", path)).unwrap();        
-        let mut overrides: Vec<Override> = override_filenames.flatten()
-            .map(|f| serde_yaml::from_str::<Vec<Override>>(&std::fs::read_to_string(f).unwrap()).unwrap())
-            .flatten().collect();
+        /* The packs come after the config folders themselves, so anything in those wins */
+        let mut folders = vec![path.to_string()];
+        let mut packs: Vec<Pack> = Vec::new();
+        for pack_file in glob(&format!("{}/packs/*/pack.yaml", path)).unwrap().flatten() {
+            let pack: Pack = serde_yaml::from_str(&std::fs::read_to_string(&pack_file).unwrap()).unwrap();
+            let folder = pack_file.parent().unwrap();
+            if options.disabled_packs.iter().any(|p| folder.ends_with(p)) {
+                continue;
+            }
+            folders.push(folder.to_str().unwrap().to_string());
+            packs.push(pack);
+        }
 
-        let struct_filenames = glob(&format!("{}/structs/*.yaml", path)).unwrap();        
-        let structs: Vec<Struct> = struct_filenames.flatten()
-            .map(|f| serde_yaml::from_str::<Vec<Struct>>(&std::fs::read_to_string(f).unwrap()).unwrap())
-            .flatten().collect();
+        let labels: Vec<Label> = load_folder(&folders, "labels");
+        let mut overrides: Vec<Override> = load_folder(&folders, "overrides");
+        let structs: Vec<Struct> = load_folder(&folders, "structs");
+        let constants: Vec<ConstantSet> = load_folder(&folders, "constants");
+        let instruction_sets: Vec<InstructionSet> = load_folder(&folders, "instructions");
 
         /* Generate overrides from pointer labels with a length defined */
         let mut generated_overrides: Vec<Override> = labels.iter()
@@ -77,20 +206,28 @@ impl Config {
             .map(|l| Override {
                 addr: OverrideAddr::Range(vec![l.addr, l.addr + (l.length.unwrap() * 2)]),
                 db: Some(l.addr >> 16),
-                _struct: None,
                 _type: Some(if l.label_type.clone().unwrap() == "PointerTable" { "Pointer".to_string() } else { "Data".to_string() }),
-                opcode: None
+                ..Default::default()
             }).collect();
         overrides.append(&mut generated_overrides);
 
-        Config { labels, overrides, structs }
+        Config { labels, overrides, structs, constants, instruction_sets, packs, options, byte_refs: HashMap::new(), struct_tags: HashMap::new(), immediate_enums: HashMap::new() }
     }
     
     pub fn get_override(&self, addr: u64) -> Option<&Override> {
-        self.overrides.iter().find(|o| match &o.addr {
-            OverrideAddr::Address(a) if *a == addr => true,
-            OverrideAddr::Range(r) if addr >= r[0] && addr <= r[1] => true,
-            _ => false
-        })
+        self.overrides.iter().find(|o| o.dp.is_none() && o.contains(addr))
+    }
+
+    /* Direct page overrides can overlap regular overrides, so they are kept as separate entries */
+    pub fn get_direct_page(&self, addr: u64) -> Option<u64> {
+        self.overrides.iter().find(|o| o.dp.is_some() && o.contains(addr)).and_then(|o| o.dp)
     }
-}
\ No newline at end of file
+}
+
+/* Every YAML file in a sub-folder of the given config folders, a list of entries each */
+fn load_folder<T: DeserializeOwned>(folders: &[String], sub_folder: &str) -> Vec<T> {
+    folders.iter()
+        .flat_map(|folder| glob(&format!("{}/{}/*.yaml", folder, sub_folder)).unwrap().flatten())
+        .flat_map(|f| serde_yaml::from_str::<Vec<T>>(&std::fs::read_to_string(f).unwrap()).unwrap())
+        .collect()
+}
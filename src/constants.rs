@@ -0,0 +1,122 @@
+use std::{collections::{BTreeMap, HashMap}, fs::File, io::Write};
+
+use crate::{code::Code, config::{Config, ConstantSet}, line::Line, opcode::AddrMode, registers::{self, Registers}};
+
+pub fn find<'a>(config: &'a Config, name: &str) -> Option<&'a ConstantSet> {
+    config.constants.iter().find(|c| c.name == name)
+}
+
+fn define(set: &ConstantSet, name: &str) -> String {
+    format!("!{}_{}", set.name, name)
+}
+
+/* Writes a value using the constants of a set: the matching name for an enum, the flags that
+   make it up otherwise. None when the set can't explain the value, it's written as a number then. */
+pub fn expr(config: &Config, set_name: &str, value: u64) -> Option<String> {
+    let set = find(config, set_name)?;
+
+    if let Some(v) = set.values.iter().find(|v| v.value == value) {
+        return Some(define(set, &v.name));
+    }
+
+    if set._type != "Flags" || value == 0 {
+        return None;
+    }
+
+    let mut parts = Vec::new();
+    let mut left = value;
+    for v in set.values.iter().filter(|v| v.value != 0 && value & v.value == v.value) {
+        parts.push(define(set, &v.name));
+        left &= !v.value;
+    }
+
+    if parts.is_empty() {
+        return None;
+    }
+    if left != 0 {
+        parts.push(format!("${:X}", left));
+    }
+
+    Some(parts.join("|"))
+}
+
+pub fn write_defines(config: &Config, filename: &str) {
+    let mut output_file = File::create(filename).unwrap();
+    for set in &config.constants {
+        let _ = writeln!(output_file, "; {} ({})", set.name, set._type);
+        for v in &set.values {
+            let _ = writeln!(output_file, "{} = ${:02X}", define(set, &v.name), v.value);
+        }
+        let _ = writeln!(output_file);
+    }
+}
+
+/* Which register an instruction loads, stores or compares, by name */
+fn register(name: &str) -> Option<usize> {
+    match name {
+        "CMP" | "AND" | "ORA" | "EOR" | "BIT" => Some(0),
+        "CPX" => Some(1),
+        "CPY" => Some(2),
+        _ => registers::register(name)
+    }
+}
+
+/* What a register holds: the immediate loaded by an instruction or a value with constants */
+#[derive(Clone, Copy)]
+enum Held<'a> {
+    Immediate(u64),
+    Enum(&'a String)
+}
+
+/* Finds the immediates that go with an address that has constants: values loaded and then stored
+   to it, and values compared with or masked against what was loaded from it. Each register
+   remembers the immediate it was loaded with or the constants of the address it was loaded from. */
+pub fn immediate_enums(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> HashMap<u64, String> {
+    let mut result: HashMap<u64, String> = HashMap::new();
+    let enums: HashMap<u64, &String> = config.labels.iter()
+        .filter_map(|l| l._enum.as_ref().map(|e| (l.addr, e)))
+        .collect();
+    if enums.is_empty() {
+        return result;
+    }
+
+    let code: Vec<&Code> = lines.values().flatten().filter_map(|l| match l {
+        Line::Code(c) => Some(c),
+        _ => None
+    }).collect();
+    let mut regs: Registers<Held> = Registers::new(&code);
+
+    for c in code {
+        regs.start(c);
+
+        let name = c.opcode.name;
+        match (register(name), &c.opcode.addr_mode) {
+            (Some(r), AddrMode::Immediate) => {
+                match (name, regs.values[r]) {
+                    ("LDA" | "LDX" | "LDY", _) => regs.values[r] = Some(Held::Immediate(c.address)),
+                    (_, Some(Held::Enum(e))) => {
+                        result.entry(c.address).or_insert(e.to_string());
+                    },
+                    ("AND" | "ORA" | "EOR", _) => regs.values[r] = None,
+                    _ => ()
+                }
+            },
+            (Some(r), _) => {
+                let target = c.operand_addr(config).and_then(|a| enums.get(&a));
+                match (name, regs.values[r]) {
+                    ("STA" | "STX" | "STY", Some(Held::Immediate(imm))) => {
+                        if let Some(e) = target {
+                            result.entry(imm).or_insert(e.to_string());
+                        }
+                    },
+                    ("LDA" | "LDX" | "LDY", _) => regs.values[r] = target.map(|e| Held::Enum(e)),
+                    ("AND" | "ORA" | "EOR", _) => regs.values[r] = None,
+                    _ => ()
+                }
+            },
+            (None, _) => regs.clobber(c)
+        }
+    }
+
+    result
+}
@@ -1,4 +1,4 @@
-use crate::{config::Config, label::LABELS};
+use crate::{byteref, config::Config, constants, label::LABELS, palette, structs};
 use if_chain::if_chain;
 
 #[derive(Debug, Clone)]
@@ -15,6 +15,14 @@ impl DataVal {
             DataVal::DL(l) => *l as u64
         }
     }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            DataVal::DB(_) => 1,
+            DataVal::DW(_) => 2,
+            DataVal::DL(_) => 3
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -25,6 +33,76 @@ pub struct Data {
 }
 
 impl Data {
+    /* Every value together with the address it sits at */
+    pub fn entries(&self) -> Vec<(u64, &DataVal)> {
+        let mut cur_pc = self.address;
+        self.data.iter().map(|d| {
+            let entry = (cur_pc, d);
+            cur_pc += d.size();
+            entry
+        }).collect()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.iter().flat_map(|d| {
+            let v = d.as_u64();
+            (0..d.size()).map(move |i| ((v >> (i * 8)) & 0xFF) as u8)
+        }).collect()
+    }
+
+    /* For values that are offsets from a base address: the address they point at and the base */
+    pub fn offset_target(&self, cur_pc: u64, d: &DataVal, config: &Config) -> Option<(u64, u64)> {
+        let base = match config.get_override(cur_pc) {
+            Some(ov) if ov._type.as_deref() == Some("Offset") => ov.base,
+            _ => config.struct_tags.get(&cur_pc).filter(|t| t.leaf._type == "Offset").and_then(|t| t.leaf.base)
+        }?;
+
+        Some(((base & 0xFF0000) | ((base + d.as_u64()) & 0xFFFF), base))
+    }
+
+    /* Values that get written as labels: where each one sits and the label it points to */
+    pub fn pointers(&self, config: &Config) -> Vec<(u64, u64)> {
+        let labels = LABELS.lock().unwrap();
+        let mut pointers = Vec::new();
+
+        for (cur_pc, d) in self.entries() {
+            if let Some(r) = config.byte_refs.get(&cur_pc) {
+                if labels.contains_key(&r.target) {
+                    pointers.push((cur_pc, r.target));
+                }
+                continue;
+            }
+
+            if let Some((target, _)) = self.offset_target(cur_pc, d, config) {
+                if labels.contains_key(&target) {
+                    pointers.push((cur_pc, target));
+                }
+                continue;
+            }
+
+            if let DataVal::DL(dl) = d {
+                if labels.contains_key(&(*dl as u64)) {
+                    pointers.push((cur_pc, *dl as u64));
+                    continue;
+                }
+            }
+
+            let label_addr = match config.get_override(cur_pc) {
+                Some(ov) if matches!(ov._type.as_deref(), Some("Pointer") | Some("Data")) => {
+                    let db = ov.db.unwrap_or(cur_pc >> 16);
+                    Some((d.as_u64() & 0xFFFF_u64) | (db << 16))
+                },
+                _ => config.struct_tags.get(&cur_pc).and_then(|t| structs::pointer_target(t, cur_pc, d.as_u64()))
+            };
+
+            if let Some(label_addr) = label_addr.filter(|a| labels.contains_key(a)) {
+                pointers.push((cur_pc, label_addr));
+            }
+        }
+
+        pointers
+    }
+
     pub fn to_string(&self, config: &Config) -> String {
         let mut last_data_cmd = "";
         let mut output = "    ".to_string();
@@ -44,7 +122,7 @@ impl Data {
             if !first_cmd && labels.contains_key(&cur_pc) {
                 /* There's a label for this address, add it into the data */
                 output.push_str(&format!(" : {}: ", labels[&cur_pc].name));
-                let mut lbl = labels.get_mut(&cur_pc).unwrap();
+                let lbl = labels.get_mut(&cur_pc).unwrap();
                 lbl.assigned = true;
                 first_cmd = true;
                 first_val = true;
@@ -58,56 +136,58 @@ impl Data {
                 first_cmd = false;
             }
 
-            if_chain! {
-                if let DataVal::DL(dl) = d;
-                if labels.contains_key(&(*dl as u64));
-                then {
-                    output.push_str(&format!("{}{}", if first_val { "" } else { "," }, labels[&(*dl as u64)].name));
-                } else {
-                    if_chain! {
-                        if let Some(ov) = config.get_override(cur_pc);
-                        if let Some(t) = &ov._type;
-                        if t == "Pointer" || t == "Data";
-                        then {
-                            let db = ov.db.unwrap_or(cur_pc >> 16);
-                            let label_addr = (d.as_u64() & 0xFFFF_u64) | (db << 16);
-                            if labels.contains_key(&label_addr) {
-                                output.push_str(&format!("{}{}", if first_val { "" } else { "," }, labels[&label_addr].name));
+            let byte_ref = config.byte_refs.get(&cur_pc).and_then(|r| labels.get(&r.target).map(|l| byteref::expr(&l.name, r.part, data_len)));
+            let offset = self.offset_target(cur_pc, d, config).and_then(|(target, base)| {
+                labels.get(&target).zip(labels.get(&base)).map(|(t, b)| format!("{}-{}", t.name, b.name))
+            });
+            if let Some(expr) = byte_ref.or(offset) {
+                output.push_str(&format!("{}{}", if first_val { "" } else { "," }, expr));
+            } else {
+                if_chain! {
+                    if let DataVal::DL(dl) = d;
+                    if labels.contains_key(&(*dl as u64));
+                    then {
+                        output.push_str(&format!("{}{}", if first_val { "" } else { "," }, labels[&(*dl as u64)].name));
+                    } else {
+                        if_chain! {
+                            if let Some(ov) = config.get_override(cur_pc);
+                            if let Some(t) = &ov._type;
+                            if t == "Pointer" || t == "Data";
+                            then {
+                                let db = ov.db.unwrap_or(cur_pc >> 16);
+                                let label_addr = (d.as_u64() & 0xFFFF_u64) | (db << 16);
+                                if labels.contains_key(&label_addr) {
+                                    output.push_str(&format!("{}{}", if first_val { "" } else { "," }, labels[&label_addr].name));
+                                } else {
+                                    match d {                
+                                        DataVal::DB(db) => output.push_str(&format!("{}${:02X}", if first_val { "" } else { "," }, db)),
+                                        DataVal::DW(dw) => output.push_str(&format!("{}${:04X}", if first_val { "" } else { "," }, dw)),
+                                        DataVal::DL(dl) => output.push_str(&format!("{}${:06X}", if first_val { "" } else { "," }, dl)),
+                                    }       
+                                }                                 
                             } else {
-                                match d {                
-                                    DataVal::DB(db) => output.push_str(&format!("{}${:02X}", if first_val { "" } else { "," }, db)),
-                                    DataVal::DW(dw) => output.push_str(&format!("{}${:04X}", if first_val { "" } else { "," }, dw)),
-                                    DataVal::DL(dl) => output.push_str(&format!("{}${:06X}", if first_val { "" } else { "," }, dl)),
-                                }       
-                            }                                 
-                        } else {
-                            if_chain! {
-                                if let Some(ov) = config.get_override(cur_pc);
-                                if let Some(t) = &ov._type;
-                                if t == "Struct";
-                                if let Some(st) = config.structs.iter().find(|s| &s.name == ov._struct.as_ref().unwrap_or(&"".to_string()));
-                                then {
-                                    let last_field = &st.fields[st.fields.len() - 1];
-                                    let st_len = last_field.offset + last_field.length;
-                                    let cur_offset = cur_pc - self.address;
-                                    let cur_st_offset = cur_offset % st_len;
-                                    let field = &st.fields.iter().find(|f| f.offset == cur_st_offset).unwrap();
-                                    let db = field.db.unwrap_or(cur_pc >> 16);                                    
-                                    let label_addr = if field.length < 3 { (d.as_u64() & 0xFFFF_u64) | (db << 16) } else { d.as_u64() };
-                                    if field._type == "Pointer" && (label_addr & 0xFFFF_u64) >= 0x8000 && labels.contains_key(&label_addr) {
-                                        output.push_str(&format!("{}{}", if first_val { "" } else { "," }, labels[&label_addr].name));
+                                if_chain! {
+                                    if let Some(tag) = config.struct_tags.get(&cur_pc);
+                                    then {
+                                        let label_addr = structs::pointer_target(tag, cur_pc, d.as_u64()).unwrap_or(0);
+                                        let constant = tag.leaf._enum.as_ref().and_then(|e| constants::expr(config, e, d.as_u64()));
+                                        if let Some(constant) = constant {
+                                            output.push_str(&format!("{}{}", if first_val { "" } else { "," }, constant));
+                                        } else if labels.contains_key(&label_addr) {
+                                            output.push_str(&format!("{}{}", if first_val { "" } else { "," }, labels[&label_addr].name));
+                                        } else {
+                                            match d {                
+                                                DataVal::DB(db) => output.push_str(&format!("{}${:02X}", if first_val { "" } else { "," }, db)),
+                                                DataVal::DW(dw) => output.push_str(&format!("{}${:04X}", if first_val { "" } else { "," }, dw)),
+                                                DataVal::DL(dl) => output.push_str(&format!("{}${:06X}", if first_val { "" } else { "," }, dl)),
+                                            }       
+                                        }                                         
                                     } else {
                                         match d {                
                                             DataVal::DB(db) => output.push_str(&format!("{}${:02X}", if first_val { "" } else { "," }, db)),
                                             DataVal::DW(dw) => output.push_str(&format!("{}${:04X}", if first_val { "" } else { "," }, dw)),
                                             DataVal::DL(dl) => output.push_str(&format!("{}${:06X}", if first_val { "" } else { "," }, dl)),
-                                        }       
-                                    }                                         
-                                } else {
-                                    match d {                
-                                        DataVal::DB(db) => output.push_str(&format!("{}${:02X}", if first_val { "" } else { "," }, db)),
-                                        DataVal::DW(dw) => output.push_str(&format!("{}${:04X}", if first_val { "" } else { "," }, dw)),
-                                        DataVal::DL(dl) => output.push_str(&format!("{}${:06X}", if first_val { "" } else { "," }, dl)),
+                                        }
                                     }
                                 }
                             }
@@ -120,8 +200,22 @@ impl Data {
             cur_pc += data_len;
         }
 
+        /* Struct records name their fields */
+        let mut fields: Vec<String> = self.entries().iter()
+            .filter_map(|(a, _)| config.struct_tags.get(a))
+            .map(|t| if t.is_terminator() { "terminator".to_string() } else { t.leaf.path.to_string() })
+            .collect();
+
+        /* Palettes get the colours they hold */
+        if config.get_override(self.address).map(|ov| ov._type.as_deref() == Some("Palette")).unwrap_or(false) {
+            fields.push(palette::comment(&self.data));
+        }
+        if !fields.is_empty() {
+            output.push_str(&format!(" ; {}", fields.join(", ")));
+        }
+
         if let Some(comment) = &self.comment {
-            output.push_str(&format!(" ; | {:06X} | {}", self.address, comment));
+            output.push_str(&format!("{} | {:06X} | {}", if fields.is_empty() { " ;" } else { "" }, self.address, comment));
         }
 
         output
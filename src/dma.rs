@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+use if_chain::if_chain;
+
+use crate::{code::Code, config::{Config, Override, OverrideAddr}, hwregs, label::LABELS, line::Line, opcode::AddrMode, registers::{immediates, Loaded, Registers, Stored}};
+
+/* A DMA or HDMA transfer, put together from the channel registers written before it was started */
+#[derive(Debug)]
+pub struct Transfer {
+    pub address: u64,
+    pub channel: u64,
+    pub hdma: bool,
+    pub params: Option<u64>,
+    pub dest: Option<u64>,
+    pub source: Option<u64>,
+    pub source_insn: Option<u64>,
+    pub size: Option<u64>
+}
+
+impl Transfer {
+    /* Sources in ROM, the data they point at only exists as part of the bank */
+    fn rom_source(&self) -> Option<u64> {
+        self.source.filter(|s| (s & 0xFFFF) >= 0x8000 && !(0x70..=0x7F).contains(&(s >> 16)))
+    }
+
+    fn describe(&self) -> String {
+        let known = |v: Option<u64>, f: &dyn Fn(u64) -> String| v.map(f).unwrap_or("?".to_string());
+        let source = known(self.source, &|s| match LABELS.lock().unwrap().get(&s) {
+            Some(l) => l.name.to_string(),
+            None => format!("${:06X}", s)
+        });
+        let dest = known(self.dest, &|d| hwregs::name(0x2100 | d).unwrap_or(format!("$21{:02X}", d)));
+        let params = self.params.and_then(|p| hwregs::decode(0x4300, p)).unwrap_or("?".to_string());
+
+        if self.hdma {
+            format!("; HDMA ch{}: table {} -> {}, {}", self.channel, source, dest, params)
+        } else {
+            let size = known(self.size, &|s| format!("${:04X} bytes", if s == 0 { 0x10000 } else { s }));
+            format!("; DMA ch{}: {} -> {}, {}, {}", self.channel, source, dest, size, params)
+        }
+    }
+}
+
+/* Follows the writes to the DMA channel registers ($43x0-$43xA) and picks up the transfers when
+   MDMAEN/HDMAEN get written. Only immediate values are followed, whatever isn't known is left out. */
+pub fn discover(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<Transfer> {
+    let mut transfers = Vec::new();
+
+    let code: Vec<&Code> = lines.values().flatten().filter_map(|l| match l {
+        Line::Code(c) => Some(c),
+        _ => None
+    }).collect();
+
+    let mut regs: Registers<Loaded> = Registers::new(&code);
+    let mut channel_regs: HashMap<u64, (u64, u64)> = HashMap::new();
+
+    for c in code {
+        if regs.start(c) {
+            channel_regs.clear();
+        }
+
+        let stored = match immediates(&mut regs, c) {
+            Some(stored) => stored,
+            None => continue
+        };
+
+        if_chain! {
+            if matches!(c.opcode.addr_mode, AddrMode::Absolute | AddrMode::AbsoluteLong | AddrMode::Direct);
+            if let Some(target) = c.operand_addr(config).map(|t| t & 0xFFFF).filter(|t| (0x2000..0x8000).contains(t));
+            then {
+                let value = match stored {
+                    Stored::Known(v) => v,
+                    Stored::Unknown(size) => {
+                        /* Unknown values make the registers they land on unknown too */
+                        for i in 0..size {
+                            channel_regs.remove(&(target + i));
+                        }
+                        continue;
+                    }
+                };
+
+                match target {
+                    0x4300..=0x437F => {
+                        for i in 0..value.size {
+                            channel_regs.insert(target + i, ((value.value >> (i * 8)) & 0xFF, value.insn));
+                        }
+                    },
+                    0x420B | 0x420C => {
+                        for channel in (0..8).filter(|ch| value.value & (1 << ch) != 0) {
+                            let reg = |r: u64| channel_regs.get(&(0x4300 | (channel << 4) | r)).copied();
+                            let word = |r: u64| reg(r).zip(reg(r + 1)).map(|((lo, _), (hi, _))| (hi << 8) | lo);
+
+                            let source = word(2).zip(reg(4)).map(|(low, (bank, _))| (bank << 16) | low);
+                            if source.is_none() && (0..7).all(|r| reg(r).is_none()) {
+                                continue;
+                            }
+                            transfers.push(Transfer {
+                                address: c.address,
+                                channel,
+                                hdma: target == 0x420C,
+                                params: reg(0).map(|(v, _)| v),
+                                dest: reg(1).map(|(v, _)| v),
+                                source,
+                                source_insn: source.and(reg(2)).map(|(_, insn)| insn),
+                                size: word(5)
+                            });
+                        }
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    transfers
+}
+
+/* Data overrides on the instructions that load the source address of a transfer, so the
+   address is written with a label. Instructions that already have an override are left alone. */
+pub fn overrides(transfers: &[Transfer], config: &Config) -> Vec<Override> {
+    let mut overrides: Vec<Override> = Vec::new();
+    for t in transfers {
+        if_chain! {
+            if let Some(source) = t.source;
+            if t.rom_source().is_some() || LABELS.lock().unwrap().contains_key(&source);
+            if let Some(insn) = t.source_insn;
+            if config.get_override(insn).is_none() && !overrides.iter().any(|o| o.contains(insn));
+            then {
+                overrides.push(Override {
+                    addr: OverrideAddr::Address(insn),
+                    db: Some(source >> 16),
+                    _type: Some("Data".to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    overrides
+}
+
+/* Renames the generated labels of the data that gets transferred after what it's used for */
+pub fn name_labels(transfers: &[Transfer]) {
+    let mut labels = LABELS.lock().unwrap();
+    for t in transfers {
+        if let Some(source) = t.rom_source() {
+            if let Some(label) = labels.get_mut(&source).filter(|l| l.name == format!("DAT_{:06X}", source)) {
+                label.name = format!("{}_{:06X}", if t.hdma { "HDMATBL" } else { "DMASRC" }, source);
+            }
+        }
+    }
+}
+
+/* Puts a comment describing each transfer above the write that starts it */
+pub fn annotate(lines: &mut BTreeMap<u64, Vec<Line>>, transfers: &[Transfer]) {
+    for t in transfers {
+        if let Some(line) = lines.get_mut(&t.address) {
+            let pos = line.iter().position(|l| matches!(l, Line::Code(_))).unwrap_or(0);
+            line.insert(pos, Line::Comment(t.describe()));
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::{BTreeMap, BTreeSet, HashSet}, fs::File, io::Write};
+use serde::Serialize;
+
+use crate::{code::{ArgType, Code}, config::{Config, OverrideAddr}, label::{LabelType, LABELS}, line::Line, opcode::AddrMode};
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: u64,
+    pub to: u64,
+    pub kind: &'static str
+}
+
+#[derive(Debug, Serialize)]
+pub struct Node {
+    pub address: u64,
+    pub name: String
+}
+
+#[derive(Debug, Serialize)]
+pub struct CallGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>
+}
+
+#[derive(Debug, Serialize)]
+pub struct Successor {
+    pub to: u64,
+    pub kind: &'static str
+}
+
+#[derive(Debug, Serialize)]
+pub struct Block {
+    pub start: u64,
+    pub end: u64,
+    pub successors: Vec<Successor>
+}
+
+#[derive(Debug, Serialize)]
+pub struct Routine {
+    pub address: u64,
+    pub name: String,
+    pub blocks: Vec<Block>
+}
+
+pub struct Graphs {
+    pub calls: CallGraph,
+    pub routines: Vec<Routine>
+}
+
+fn label_name(addr: u64) -> String {
+    let labels = LABELS.lock().unwrap();
+    match labels.get(&addr) {
+        Some(l) if !l.name.starts_with('.') => l.name.to_string(),
+        _ => format!("{:06X}", addr)
+    }
+}
+
+/* Entries of a pointer table that has been described by an override */
+pub fn table_targets(lines: &BTreeMap<u64, Vec<Line>>, config: &Config, table_addr: u64) -> Vec<u64> {
+    match config.get_override(table_addr) {
+        Some(ov) if ov._type.as_deref() == Some("Pointer") => {
+            let (start, end) = match &ov.addr {
+                OverrideAddr::Range(r) => (r[0], r[1]),
+                OverrideAddr::Address(a) => (*a, *a + 1)
+            };
+            let db = ov.db.unwrap_or(table_addr >> 16);
+
+            lines.range(start..=end).flat_map(|(_, l)| l).filter_map(|l| match l {
+                Line::Data(d) => Some(d.entries()),
+                _ => None
+            }).flatten()
+            .filter(|(a, _)| *a >= start && *a <= end)
+            .map(|(_, d)| (db << 16) | (d.as_u64() & 0xFFFF))
+            .collect()
+        },
+        _ => Vec::new()
+    }
+}
+
+fn is_conditional_branch(c: &Code) -> bool {
+    c.opcode.addr_mode == AddrMode::Relative && c.opcode.name != "BRA"
+}
+
+fn ends_block(c: &Code) -> bool {
+    (matches!(c.opcode.addr_mode, AddrMode::Relative | AddrMode::RelativeLong) && c.opcode.name != "PER") ||
+        matches!(c.opcode.name, "JMP" | "JML" | "RTS" | "RTL" | "RTI" | "STP")
+}
+
+pub fn build(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> Graphs {
+    let code: Vec<&Code> = lines.values().flatten().filter_map(|l| match l {
+        Line::Code(c) => Some(c),
+        _ => None
+    }).collect();
+    let code_addrs: HashSet<u64> = code.iter().map(|c| c.address).collect();
+
+    let table_of = |c: &Code| -> Vec<u64> {
+        match (&c.arg, &c.opcode.addr_mode) {
+            (ArgType::Address(a), AddrMode::AbsoluteIndexedIndirect) =>
+                table_targets(lines, config, (c.address & 0xFF0000) | (a & 0xFFFF)),
+            _ => Vec::new()
+        }
+    };
+
+    /* Routines start at routine headers, subroutine labels and anything that gets called */
+    let mut entries: BTreeSet<u64> = BTreeSet::new();
+    let mut after_header = false;
+    for line in lines.values().flatten() {
+        match line {
+            Line::Comment(s) if s.starts_with(";;;") => after_header = true,
+            Line::Code(c) if after_header => {
+                entries.insert(c.address);
+                after_header = false;
+            },
+            _ => ()
+        }
+    }
+
+    {
+        let labels = LABELS.lock().unwrap();
+        entries.extend(labels.values()
+            .filter(|l| l.label_type == LabelType::Subroutine && code_addrs.contains(&l.address))
+            .map(|l| l.address));
+    }
+
+    for c in &code {
+        match c.opcode.name {
+            "JSR" | "JSL" => {
+                entries.extend(c.branch_target().filter(|t| code_addrs.contains(t)));
+                if c.opcode.name == "JSR" {
+                    entries.extend(table_of(c).into_iter().filter(|t| code_addrs.contains(t)));
+                }
+            },
+            _ => ()
+        }
+    }
+
+    let routine_of = |addr: u64| -> Option<u64> {
+        entries.range(..=addr).next_back().copied().filter(|r| r >> 16 == addr >> 16)
+    };
+
+    /* Call graph */
+    let mut edges: BTreeSet<Edge> = BTreeSet::new();
+    let mut bodies: BTreeMap<u64, Vec<&Code>> = BTreeMap::new();
+    for c in &code {
+        let routine = match routine_of(c.address) {
+            Some(r) => r,
+            None => continue
+        };
+        bodies.entry(routine).or_default().push(c);
+
+        match (c.opcode.name, c.branch_target()) {
+            ("JSR", Some(target)) | ("JSL", Some(target)) => {
+                edges.insert(Edge { from: routine, to: target, kind: c.opcode.name });
+            },
+            (_, Some(target)) if routine_of(target) != Some(routine) => {
+                edges.insert(Edge { from: routine, to: target, kind: "JMP" });
+            },
+            _ => {
+                for target in table_of(c) {
+                    edges.insert(Edge { from: routine, to: target, kind: "table" });
+                }
+            }
+        }
+    }
+
+    let mut node_addrs: BTreeSet<u64> = entries.iter().copied().collect();
+    node_addrs.extend(edges.iter().map(|e| e.to));
+    let nodes = node_addrs.into_iter().map(|address| Node { address, name: label_name(address) }).collect();
+
+    /* Control flow graphs, split into basic blocks at branch targets and after control flow */
+    let mut routines = Vec::new();
+    for (routine, body) in &bodies {
+        let addrs: HashSet<u64> = body.iter().map(|c| c.address).collect();
+        let mut leaders: HashSet<u64> = HashSet::new();
+        leaders.insert(body[0].address);
+        for (i, c) in body.iter().enumerate() {
+            leaders.extend(c.branch_target().filter(|t| addrs.contains(t) && !matches!(c.opcode.name, "JSR" | "JSL")));
+            leaders.extend(table_of(c).into_iter().filter(|t| addrs.contains(t)));
+            if ends_block(c) {
+                if let Some(next) = body.get(i + 1) {
+                    leaders.insert(next.address);
+                }
+            }
+        }
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, c) in body.iter().enumerate() {
+            if leaders.contains(&c.address) {
+                blocks.push(Block { start: c.address, end: c.address, successors: Vec::new() });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.end = c.address;
+
+            let next = body.get(i + 1).map(|n| n.address);
+            let block_done = next.map(|n| leaders.contains(&n)).unwrap_or(true);
+            if !block_done {
+                continue;
+            }
+
+            let target = c.branch_target().filter(|t| addrs.contains(t) && !matches!(c.opcode.name, "JSR" | "JSL"));
+            if is_conditional_branch(c) {
+                block.successors.extend(target.map(|to| Successor { to, kind: "branch" }));
+                block.successors.extend(next.map(|to| Successor { to, kind: "fallthrough" }));
+            } else if ends_block(c) {
+                block.successors.extend(target.map(|to| Successor { to, kind: "jump" }));
+                block.successors.extend(table_of(c).into_iter().filter(|t| addrs.contains(t)).map(|to| Successor { to, kind: "table" }));
+            } else {
+                block.successors.extend(next.map(|to| Successor { to, kind: "fallthrough" }));
+            }
+        }
+
+        routines.push(Routine { address: *routine, name: label_name(*routine), blocks });
+    }
+
+    Graphs { calls: CallGraph { nodes, edges: edges.into_iter().collect() }, routines }
+}
+
+pub fn export(graphs: &Graphs, path: &str) {
+    std::fs::create_dir_all(format!("{}/cfg", path)).unwrap();
+
+    let names: BTreeMap<u64, &String> = graphs.calls.nodes.iter().map(|n| (n.address, &n.name)).collect();
+    let mut output_file = File::create(format!("{}/callgraph.dot", path)).unwrap();
+    let _ = writeln!(output_file, "digraph callgraph {{\n    node [shape=box];");
+    for node in &graphs.calls.nodes {
+        let _ = writeln!(output_file, "    \"{}\";", node.name);
+    }
+    for edge in &graphs.calls.edges {
+        let _ = writeln!(output_file, "    \"{}\" -> \"{}\" [label=\"{}\"];", names[&edge.from], names[&edge.to], edge.kind);
+    }
+    let _ = writeln!(output_file, "}}");
+
+    let output_file = File::create(format!("{}/callgraph.json", path)).unwrap();
+    serde_json::to_writer_pretty(output_file, &graphs.calls).unwrap();
+
+    let output_file = File::create(format!("{}/cfg.json", path)).unwrap();
+    serde_json::to_writer_pretty(output_file, &graphs.routines).unwrap();
+
+    for routine in &graphs.routines {
+        let mut output_file = File::create(format!("{}/cfg/{}.dot", path, routine.name)).unwrap();
+        let _ = writeln!(output_file, "digraph \"{}\" {{\n    node [shape=box];", routine.name);
+        for block in &routine.blocks {
+            let _ = writeln!(output_file, "    \"{:06X}\" [label=\"${:06X}-${:06X}\"];", block.start, block.start, block.end);
+            for s in &block.successors {
+                let _ = writeln!(output_file, "    \"{:06X}\" -> \"{:06X}\" [label=\"{}\"];", block.start, s.to, s.kind);
+            }
+        }
+        let _ = writeln!(output_file, "}}");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, HashMap};
+use if_chain::if_chain;
+
+use crate::{code::Code, config::Config, line::Line, opcode::AddrMode, registers::{immediates, Loaded, Registers, Stored}};
+
+/* A bitfield of a register, single bits are flags that get listed by name when they're set */
+pub struct Field {
+    pub name: &'static str,
+    pub shift: u8,
+    pub width: u8
+}
+
+pub struct Register {
+    pub address: u64,
+    pub name: &'static str,
+    pub fields: &'static [Field]
+}
+
+const fn f(name: &'static str, shift: u8, width: u8) -> Field {
+    Field { name, shift, width }
+}
+
+const fn r(address: u64, name: &'static str, fields: &'static [Field]) -> Register {
+    Register { address, name, fields }
+}
+
+const LAYERS: &[Field] = &[f("obj", 4, 1), f("bg4", 3, 1), f("bg3", 2, 1), f("bg2", 1, 1), f("bg1", 0, 1)];
+const TILEMAP: &[Field] = &[f("base", 2, 6), f("size", 0, 2)];
+const CHANNELS: &[Field] = &[f("ch7", 7, 1), f("ch6", 6, 1), f("ch5", 5, 1), f("ch4", 4, 1), f("ch3", 3, 1), f("ch2", 2, 1), f("ch1", 1, 1), f("ch0", 0, 1)];
+
+static REGISTERS: &[Register] = &[
+    r(0x2100, "INIDISP", &[f("force blank", 7, 1), f("brightness", 0, 4)]),
+    r(0x2101, "OBSEL", &[f("size", 5, 3), f("name select", 3, 2), f("base", 0, 3)]),
+    r(0x2102, "OAMADDL", &[]),
+    r(0x2103, "OAMADDH", &[f("priority rotation", 7, 1), f("table", 0, 1)]),
+    r(0x2104, "OAMDATA", &[]),
+    r(0x2105, "BGMODE", &[f("bg4 16x16", 7, 1), f("bg3 16x16", 6, 1), f("bg2 16x16", 5, 1), f("bg1 16x16", 4, 1), f("bg3 priority", 3, 1), f("mode", 0, 3)]),
+    r(0x2106, "MOSAIC", &[f("size", 4, 4), f("bg4", 3, 1), f("bg3", 2, 1), f("bg2", 1, 1), f("bg1", 0, 1)]),
+    r(0x2107, "BG1SC", TILEMAP),
+    r(0x2108, "BG2SC", TILEMAP),
+    r(0x2109, "BG3SC", TILEMAP),
+    r(0x210A, "BG4SC", TILEMAP),
+    r(0x210B, "BG12NBA", &[f("bg2 base", 4, 4), f("bg1 base", 0, 4)]),
+    r(0x210C, "BG34NBA", &[f("bg4 base", 4, 4), f("bg3 base", 0, 4)]),
+    r(0x210D, "BG1HOFS", &[]),
+    r(0x210E, "BG1VOFS", &[]),
+    r(0x210F, "BG2HOFS", &[]),
+    r(0x2110, "BG2VOFS", &[]),
+    r(0x2111, "BG3HOFS", &[]),
+    r(0x2112, "BG3VOFS", &[]),
+    r(0x2113, "BG4HOFS", &[]),
+    r(0x2114, "BG4VOFS", &[]),
+    r(0x2115, "VMAIN", &[f("increment on high", 7, 1), f("remap", 2, 2), f("step", 0, 2)]),
+    r(0x2116, "VMADDL", &[]),
+    r(0x2117, "VMADDH", &[]),
+    r(0x2118, "VMDATAL", &[]),
+    r(0x2119, "VMDATAH", &[]),
+    r(0x211A, "M7SEL", &[f("screen over", 6, 2), f("flip v", 1, 1), f("flip h", 0, 1)]),
+    r(0x211B, "M7A", &[]),
+    r(0x211C, "M7B", &[]),
+    r(0x211D, "M7C", &[]),
+    r(0x211E, "M7D", &[]),
+    r(0x211F, "M7X", &[]),
+    r(0x2120, "M7Y", &[]),
+    r(0x2121, "CGADD", &[]),
+    r(0x2122, "CGDATA", &[]),
+    r(0x2123, "W12SEL", &[f("bg2 w2 enable", 7, 1), f("bg2 w2 invert", 6, 1), f("bg2 w1 enable", 5, 1), f("bg2 w1 invert", 4, 1),
+                          f("bg1 w2 enable", 3, 1), f("bg1 w2 invert", 2, 1), f("bg1 w1 enable", 1, 1), f("bg1 w1 invert", 0, 1)]),
+    r(0x2124, "W34SEL", &[f("bg4 w2 enable", 7, 1), f("bg4 w2 invert", 6, 1), f("bg4 w1 enable", 5, 1), f("bg4 w1 invert", 4, 1),
+                          f("bg3 w2 enable", 3, 1), f("bg3 w2 invert", 2, 1), f("bg3 w1 enable", 1, 1), f("bg3 w1 invert", 0, 1)]),
+    r(0x2125, "WOBJSEL", &[f("color w2 enable", 7, 1), f("color w2 invert", 6, 1), f("color w1 enable", 5, 1), f("color w1 invert", 4, 1),
+                           f("obj w2 enable", 3, 1), f("obj w2 invert", 2, 1), f("obj w1 enable", 1, 1), f("obj w1 invert", 0, 1)]),
+    r(0x2126, "WH0", &[]),
+    r(0x2127, "WH1", &[]),
+    r(0x2128, "WH2", &[]),
+    r(0x2129, "WH3", &[]),
+    r(0x212A, "WBGLOG", &[f("bg4 logic", 6, 2), f("bg3 logic", 4, 2), f("bg2 logic", 2, 2), f("bg1 logic", 0, 2)]),
+    r(0x212B, "WOBJLOG", &[f("color logic", 2, 2), f("obj logic", 0, 2)]),
+    r(0x212C, "TM", LAYERS),
+    r(0x212D, "TS", LAYERS),
+    r(0x212E, "TMW", LAYERS),
+    r(0x212F, "TSW", LAYERS),
+    r(0x2130, "CGWSEL", &[f("clip to black", 6, 2), f("prevent math", 4, 2), f("add subscreen", 1, 1), f("direct color", 0, 1)]),
+    r(0x2131, "CGADSUB", &[f("subtract", 7, 1), f("half", 6, 1), f("backdrop", 5, 1), f("obj", 4, 1), f("bg4", 3, 1), f("bg3", 2, 1), f("bg2", 1, 1), f("bg1", 0, 1)]),
+    r(0x2132, "COLDATA", &[f("blue", 7, 1), f("green", 6, 1), f("red", 5, 1), f("intensity", 0, 5)]),
+    r(0x2133, "SETINI", &[f("external sync", 7, 1), f("extbg", 6, 1), f("pseudo hi-res", 3, 1), f("overscan", 2, 1), f("obj interlace", 1, 1), f("interlace", 0, 1)]),
+    r(0x2134, "MPYL", &[]),
+    r(0x2135, "MPYM", &[]),
+    r(0x2136, "MPYH", &[]),
+    r(0x2137, "SLHV", &[]),
+    r(0x2138, "RDOAM", &[]),
+    r(0x2139, "RDVRAML", &[]),
+    r(0x213A, "RDVRAMH", &[]),
+    r(0x213B, "RDCGRAM", &[]),
+    r(0x213C, "OPHCT", &[]),
+    r(0x213D, "OPVCT", &[]),
+    r(0x213E, "STAT77", &[]),
+    r(0x213F, "STAT78", &[]),
+    r(0x2140, "APUIO0", &[]),
+    r(0x2141, "APUIO1", &[]),
+    r(0x2142, "APUIO2", &[]),
+    r(0x2143, "APUIO3", &[]),
+    r(0x2180, "WMDATA", &[]),
+    r(0x2181, "WMADDL", &[]),
+    r(0x2182, "WMADDM", &[]),
+    r(0x2183, "WMADDH", &[]),
+    r(0x4016, "JOYSER0", &[]),
+    r(0x4017, "JOYSER1", &[]),
+    r(0x4200, "NMITIMEN", &[f("nmi", 7, 1), f("v irq", 5, 1), f("h irq", 4, 1), f("auto joypad", 0, 1)]),
+    r(0x4201, "WRIO", &[]),
+    r(0x4202, "WRMPYA", &[]),
+    r(0x4203, "WRMPYB", &[]),
+    r(0x4204, "WRDIVL", &[]),
+    r(0x4205, "WRDIVH", &[]),
+    r(0x4206, "WRDIVB", &[]),
+    r(0x4207, "HTIMEL", &[]),
+    r(0x4208, "HTIMEH", &[]),
+    r(0x4209, "VTIMEL", &[]),
+    r(0x420A, "VTIMEH", &[]),
+    r(0x420B, "MDMAEN", CHANNELS),
+    r(0x420C, "HDMAEN", CHANNELS),
+    r(0x420D, "MEMSEL", &[f("fastrom", 0, 1)]),
+    r(0x4210, "RDNMI", &[]),
+    r(0x4211, "TIMEUP", &[]),
+    r(0x4212, "HVBJOY", &[]),
+    r(0x4213, "RDIO", &[]),
+    r(0x4214, "RDDIVL", &[]),
+    r(0x4215, "RDDIVH", &[]),
+    r(0x4216, "RDMPYL", &[]),
+    r(0x4217, "RDMPYH", &[]),
+    r(0x4218, "JOY1L", &[]),
+    r(0x4219, "JOY1H", &[]),
+    r(0x421A, "JOY2L", &[]),
+    r(0x421B, "JOY2H", &[]),
+    r(0x421C, "JOY3L", &[]),
+    r(0x421D, "JOY3H", &[]),
+    r(0x421E, "JOY4L", &[]),
+    r(0x421F, "JOY4H", &[])
+];
+
+/* The registers of each DMA channel, repeated at $4300-$437F with the channel number in place of the "n".
+   $43xB-$43xF do nothing and aren't named. */
+static DMA_REGISTERS: &[Register] = &[
+    r(0x0, "DMAPn", &[f("to cpu", 7, 1), f("indirect", 6, 1), f("decrement", 4, 1), f("fixed", 3, 1), f("mode", 0, 3)]),
+    r(0x1, "BBADn", &[]),
+    r(0x2, "A1TnL", &[]),
+    r(0x3, "A1TnH", &[]),
+    r(0x4, "A1Bn", &[]),
+    r(0x5, "DASnL", &[]),
+    r(0x6, "DASnH", &[]),
+    r(0x7, "DASBn", &[]),
+    r(0x8, "A2AnL", &[]),
+    r(0x9, "A2AnH", &[]),
+    r(0xA, "NTRLn", &[])
+];
+
+fn lookup(addr: u64) -> Option<(String, &'static [Field])> {
+    let addr = addr & 0xFFFF;
+    if (0x4300..0x4380).contains(&addr) {
+        let channel = (addr >> 4) & 0x7;
+        return DMA_REGISTERS.iter().find(|r| r.address == addr & 0xF)
+            .map(|r| (r.name.replacen('n', &channel.to_string(), 1), r.fields));
+    }
+
+    REGISTERS.iter().find(|r| r.address == addr).map(|r| (r.name.to_string(), r.fields))
+}
+
+/* The register's name, for addresses in the $2100-$43FF range */
+pub fn name(addr: u64) -> Option<String> {
+    lookup(addr).map(|(name, _)| name)
+}
+
+/* Spells out the fields of a value written to a register */
+pub fn decode(addr: u64, value: u64) -> Option<String> {
+    let (_, fields) = lookup(addr)?;
+    if fields.is_empty() {
+        return None;
+    }
+
+    let parts: Vec<String> = fields.iter().filter_map(|field| {
+        let v = (value >> field.shift) & ((1 << field.width) - 1);
+        match field.width {
+            1 if v == 1 => Some(field.name.to_string()),
+            1 => None,
+            _ => Some(format!("{} {}", field.name, v))
+        }
+    }).collect();
+
+    Some(if parts.is_empty() { "all clear".to_string() } else { parts.join(", ") })
+}
+
+/* Comments the stores of known immediates to hardware registers with the fields they set.
+   A 16-bit store sets the register after it too, both get decoded. */
+pub fn annotate_writes(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config) {
+    let mut comments: HashMap<u64, String> = HashMap::new();
+    {
+        let code: Vec<&Code> = lines.values().flatten().filter_map(|l| match l {
+            Line::Code(c) => Some(c),
+            _ => None
+        }).collect();
+
+        let mut regs: Registers<Loaded> = Registers::new(&code);
+        for c in code {
+            regs.start(c);
+
+            if_chain! {
+                if let Some(Stored::Known(Loaded { value, size, .. })) = immediates(&mut regs, c);
+                if matches!(c.opcode.addr_mode, AddrMode::Absolute | AddrMode::AbsoluteLong | AddrMode::Direct);
+                if let Some(target) = c.operand_addr(config);
+                if (0x2100..0x4400).contains(&(target & 0xFFFF));
+                then {
+                    let mut decoded: Vec<String> = decode(target, value & 0xFF).into_iter().collect();
+                    if size == 2 {
+                        decoded.extend(decode(target + 1, value >> 8));
+                    }
+                    if !decoded.is_empty() {
+                        comments.insert(c.address, decoded.join("; "));
+                    }
+                }
+            }
+        }
+    }
+
+    for line in lines.values_mut().flatten() {
+        if let Line::Code(c) = line {
+            if let Some(decoded) = comments.remove(&c.address) {
+                c.comment = Some(match &c.comment {
+                    Some(comment) => format!("{} ({})", comment, decoded),
+                    None => decoded
+                });
+            }
+        }
+    }
+}
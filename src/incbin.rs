@@ -0,0 +1,254 @@
+use std::{collections::BTreeMap, fmt, fs::File, io::Write};
+
+use crate::{config::{Config, OverrideAddr}, data::{Data, DataVal}, label::{Label, LabelType, LABELS}, line::Line};
+
+/* A run of data written to a file of its own and included back, with incbin or with incsrc for
+   assembly. The labels that sat inside it are defined relative to the label at its start. */
+#[derive(Debug, Clone)]
+pub struct Binary {
+    pub directive: &'static str,
+    pub base: String,
+    pub file: String,
+    pub labels: Vec<(String, u64)>
+}
+
+impl fmt::Display for Binary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "    {} {}", self.directive, self.file)?;
+        for (name, offset) in &self.labels {
+            write!(f, "\n{} = {}+{}", name, self.base, offset)?;
+        }
+        Ok(())
+    }
+}
+
+/* Data that can go into a file as it is: nothing in it gets written as a label or a constant */
+fn is_plain(d: &Data, config: &Config) -> bool {
+    d.pointers(config).is_empty() && d.entries().iter().all(|(a, _)| {
+        !config.struct_tags.contains_key(a) && !config.byte_refs.contains_key(a) &&
+            config.get_override(*a).map(|ov| ov._type.as_deref() == Some("Binary")).unwrap_or(true)
+    })
+}
+
+/* Which Binary override an address is in, by where it starts */
+fn binary_region(config: &Config, addr: u64) -> Option<u64> {
+    config.overrides.iter()
+        .filter(|ov| ov._type.as_deref() == Some("Binary") && ov.contains(addr))
+        .map(|ov| match &ov.addr {
+            OverrideAddr::Range(r) => r[0],
+            OverrideAddr::Address(a) => *a
+        })
+        .next()
+}
+
+/* Moves runs of plain data into "<folder>/<label>.bin" files. Runs in a "Binary" override always
+   go, others once they reach the "incbin_threshold" option in bytes (0 leaves them all in place).
+   Runs end at anything that isn't plain data, at gaps and at the edges of the Binary overrides. */
+pub fn extract(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config, folder: &str) {
+    let mut runs: Vec<Vec<u64>> = Vec::new();
+    let mut run: Vec<u64> = Vec::new();
+    let mut next_addr = 0;
+    let mut region = None;
+
+    for (addr, addr_lines) in lines.iter() {
+        let data: Vec<&Data> = addr_lines.iter().filter_map(|l| match l {
+            Line::Data(d) => Some(d),
+            _ => None
+        }).collect();
+        let plain = !data.is_empty() && addr_lines.iter().all(|l| match l {
+            Line::Data(d) => is_plain(d, config),
+            Line::Comment(_) => true,
+            _ => false
+        });
+
+        let addr_region = binary_region(config, *addr);
+        if !plain || *addr != next_addr || addr_region != region {
+            runs.push(std::mem::take(&mut run));
+        }
+        if plain {
+            run.push(*addr);
+            next_addr = data.iter().map(|d| d.address + d.bytes().len() as u64).max().unwrap_or(*addr);
+            region = addr_region;
+        }
+    }
+    runs.push(run);
+
+    let threshold = config.options.incbin_threshold;
+    let mut created = false;
+    for run in runs.into_iter().filter(|r| !r.is_empty()) {
+        let start = run[0];
+        let bytes: Vec<u8> = run.iter()
+            .flat_map(|a| &lines[a])
+            .filter_map(|l| match l {
+                Line::Data(d) => Some(d.bytes()),
+                _ => None
+            })
+            .flatten()
+            .collect();
+
+        let wanted = binary_region(config, start).is_some() || (threshold > 0 && bytes.len() as u64 >= threshold);
+        if !wanted || bytes.is_empty() {
+            continue;
+        }
+
+        let end = start + bytes.len() as u64;
+        let (base, inner) = match region_labels(start, end, "BIN") {
+            Some(l) => l,
+            None => continue
+        };
+
+        if !created {
+            std::fs::create_dir_all(folder).unwrap();
+            created = true;
+        }
+        let file_name = format!("{}.bin", base.trim_start_matches('.'));
+        let mut output_file = File::create(format!("{}/{}", folder, file_name)).unwrap();
+        let _ = output_file.write_all(&bytes);
+
+        replace(lines, start, end, vec![Line::Binary(Binary { directive: "incbin", base, file: format!("data/{}", file_name), labels: inner })]);
+    }
+}
+
+/* The next address in the ROM, LoROM banks only have their upper half */
+pub fn next_address(addr: u64) -> u64 {
+    if (addr & 0xFFFF) == 0xFFFF { ((addr & 0xFF0000) + 0x10000) | 0x8000 } else { addr + 1 }
+}
+
+/* The bytes of the code and data lines from "start" on, up to the first gap */
+pub fn read_bytes(lines: &BTreeMap<u64, Vec<Line>>, start: u64, limit: u64) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut addr = start;
+    let first = lines.range(..=start).next_back().map(|(k, _)| *k).unwrap_or(start);
+    for line in lines.range(first..).flat_map(|(_, l)| l) {
+        let (address, line_bytes) = match line {
+            Line::Code(c) => (c.address, c.bytes()),
+            Line::Data(d) => (d.address, d.bytes()),
+            _ => continue
+        };
+        if address > addr || output.len() as u64 >= limit {
+            break;
+        }
+        for (i, b) in line_bytes.into_iter().enumerate() {
+            if address + i as u64 == addr && (output.len() as u64) < limit {
+                output.push(b);
+                addr = next_address(addr);
+            }
+        }
+    }
+    output
+}
+
+/* How many bytes into the ROM "addr" is from "start", LoROM banks only have their upper half */
+fn offset(start: u64, addr: u64) -> u64 {
+    ((addr >> 16) - (start >> 16)) * 0x8000 + (addr & 0xFFFF) - (start & 0xFFFF)
+}
+
+/* The label at the start of a region, made up with the prefix if there isn't one, and the labels inside
+   it with their offsets. None if one inside is local, those can't be defined with "=". */
+pub fn region_labels(start: u64, end: u64, prefix: &str) -> Option<(String, Vec<(String, u64)>)> {
+    let mut labels = LABELS.lock().unwrap();
+    let inside = |a: &u64| *a > start && *a < end;
+    if labels.iter().any(|(a, l)| inside(a) && l.name.starts_with('.') && l.label_type != LabelType::Blocked) {
+        return None;
+    }
+    let base = labels.entry(start).or_insert(Label {
+        address: start, name: format!("{}_{:06X}", prefix, start), label_type: LabelType::Data, assigned: false
+    }).name.to_string();
+    let mut inner: Vec<(String, u64)> = labels.iter_mut()
+        .filter(|(a, l)| inside(a) && l.label_type != LabelType::Blocked)
+        .map(|(a, l)| {
+            l.assigned = true;
+            (l.name.to_string(), offset(start, *a))
+        })
+        .collect();
+    inner.sort_by_key(|(_, offset)| *offset);
+    Some((base, inner))
+}
+
+/* The parts of a code or data line before "start" and from "end" on, as data. Values that cross
+   either edge are broken up into bytes. */
+fn outside(line: &Line, start: u64, end: u64) -> (Vec<DataVal>, Vec<DataVal>) {
+    let entries: Vec<(u64, DataVal)> = match line {
+        Line::Code(c) => c.bytes().into_iter().enumerate().map(|(i, b)| (c.address + i as u64, DataVal::DB(b))).collect(),
+        Line::Data(d) => d.entries().into_iter().map(|(a, v)| (a, v.clone())).collect(),
+        _ => Vec::new()
+    };
+
+    let (mut before, mut after) = (Vec::new(), Vec::new());
+    for (addr, value) in entries {
+        let size = value.size();
+        if addr + size <= start {
+            before.push(value);
+        } else if addr >= end {
+            after.push(value);
+        } else {
+            for i in 0..size {
+                let byte = DataVal::DB(((value.as_u64() >> (i * 8)) & 0xFF) as u8);
+                if addr + i < start {
+                    before.push(byte);
+                } else if addr + i >= end {
+                    after.push(byte);
+                }
+            }
+        }
+    }
+    (before, after)
+}
+
+/* Puts new lines in place of the code and data between "start" and "end". The first goes where the
+   first of them was, the comments in between stay in order around it and whatever the edge lines had
+   outside of the region stays as data. Data lines after the first go at their own address. */
+pub fn replace(lines: &mut BTreeMap<u64, Vec<Line>>, start: u64, end: u64, new_lines: Vec<Line>) {
+    let first = lines.range(..=start).next_back().map(|(k, _)| *k).unwrap_or(start);
+    let keys: Vec<u64> = lines.range(first..end).map(|(k, _)| *k).collect();
+
+    let mut new_lines = new_lines.into_iter();
+    let mut first_line = new_lines.next();
+    let mut kept: Vec<Line> = Vec::new();
+    let mut rest: Vec<Line> = Vec::new();
+    for key in keys {
+        let mut key_lines: Vec<Line> = Vec::new();
+        for line in lines.remove(&key).unwrap() {
+            let (address, comment) = match &line {
+                Line::Code(c) => (c.address, c.comment.clone()),
+                Line::Data(d) => (d.address, d.comment.clone()),
+                _ => {
+                    if kept.is_empty() && key < start { key_lines.push(line) } else if rest.is_empty() { kept.push(line) } else { rest.push(line) }
+                    continue;
+                }
+            };
+            let length = match &line {
+                Line::Code(c) => 1 + c.length as u64,
+                Line::Data(d) => d.bytes().len() as u64,
+                _ => 0
+            };
+            if address + length <= start {
+                key_lines.push(line);
+                continue;
+            }
+
+            let (before, after) = outside(&line, start, end);
+            if !before.is_empty() {
+                key_lines.push(Line::Data(Data { address, data: before, comment: comment.clone() }));
+            }
+            kept.extend(first_line.take());
+            if !after.is_empty() {
+                rest.push(Line::Data(Data { address: end, data: after, comment: None }));
+            }
+        }
+        if !key_lines.is_empty() {
+            lines.insert(key, key_lines);
+        }
+    }
+
+    kept.extend(first_line.take());
+    lines.entry(start).or_default().extend(kept);
+    for line in new_lines {
+        let address = match &line {
+            Line::Data(d) => d.address,
+            _ => start
+        };
+        lines.entry(address).or_default().push(line);
+    }
+    lines.entry(end).or_default().splice(0..0, rest);
+}
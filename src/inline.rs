@@ -0,0 +1,99 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{config::{Config, Override, OverrideAddr}, data::{Data, DataVal}, line::Line, structs::{self, Layout}};
+
+/* The data values for a struct's bytes, sized like the fields. Gaps between fields stay bytes. */
+fn values(layout: &Layout, bytes: &[u8]) -> Vec<DataVal> {
+    let mut sizes: BTreeMap<u64, u64> = layout.leaves.iter().map(|l| (l.offset, l.length)).collect();
+    let mut values = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() as u64 {
+        let size = sizes.remove(&offset).filter(|s| offset + s <= bytes.len() as u64).unwrap_or(1);
+        let v = (0..size).map(|i| (bytes[(offset + i) as usize] as u64) << (i * 8)).sum::<u64>();
+        values.push(match size {
+            2 => DataVal::DW(v as u16),
+            3 => DataVal::DL(v as u32),
+            _ => DataVal::DB(v as u8)
+        });
+        offset += size;
+    }
+    values
+}
+
+/* Subroutines with an "inline" struct on their label take arguments from the bytes right after
+   the call. The log often disassembles those bytes as code, they're turned back into data here and
+   get a Struct override, so they're written field by field and the pointers in them get labels. */
+pub fn convert_args(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<Override> {
+    let mut overrides = Vec::new();
+    let layouts: HashMap<u64, (&String, Layout)> = config.labels.iter()
+        .filter_map(|l| l.inline.as_ref().map(|s| (l.addr, s)))
+        .filter_map(|(addr, s)| structs::layout(config, s).ok().map(|layout| (addr, (s, layout))))
+        .collect();
+    if layouts.is_empty() {
+        return overrides;
+    }
+
+    let calls: Vec<(u64, &String, &Layout)> = lines.values().flatten()
+        .filter_map(|l| match l {
+            Line::Code(c) if matches!(c.opcode.name, "JSR" | "JSL") => c.branch_target()
+                .and_then(|t| layouts.get(&t))
+                .map(|(s, layout)| (c.address + 1 + c.length as u64, *s, layout)),
+            _ => None
+        })
+        .collect();
+
+    for (start, st, layout) in calls {
+        let end = start + layout.size;
+
+        /* Everything the arguments overlap, a misread instruction can run past their end */
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut comment = None;
+        for line in lines.range(start..end).flat_map(|(_, l)| l) {
+            match line {
+                Line::Code(c) => bytes.extend(c.bytes()),
+                Line::Data(d) => bytes.extend(d.bytes()),
+                _ => continue
+            }
+            comment = comment.or(match line {
+                Line::Code(c) => c.comment.clone(),
+                Line::Data(d) => d.comment.clone(),
+                _ => None
+            });
+        }
+        if (bytes.len() as u64) < layout.size {
+            /* The bytes aren't all in the log, leave whatever there was */
+            continue;
+        }
+
+        let keys: Vec<u64> = lines.range(start..end).map(|(k, _)| *k).collect();
+        for key in keys {
+            let addr_lines = lines.get_mut(&key).unwrap();
+            addr_lines.retain(|l| matches!(l, Line::Comment(_)));
+            if addr_lines.is_empty() {
+                lines.remove(&key);
+            }
+        }
+
+        lines.entry(start).or_default().push(Line::Data(Data {
+            address: start,
+            data: values(layout, &bytes[..layout.size as usize]),
+            comment
+        }));
+        if bytes.len() as u64 > layout.size {
+            lines.entry(end).or_default().push(Line::Data(Data {
+                address: end,
+                data: bytes[layout.size as usize..].iter().map(|b| DataVal::DB(*b)).collect(),
+                comment: None
+            }));
+        }
+
+        overrides.push(Override {
+            addr: OverrideAddr::Address(start),
+            _type: Some("Struct".to_string()),
+            _struct: Some(st.to_string()),
+            ..Default::default()
+        });
+    }
+
+    overrides
+}
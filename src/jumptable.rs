@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{code::{ArgType, Code}, config::{Config, Override, OverrideAddr}, label::LABELS, line::Line, opcode::AddrMode};
+
+/* Upper limit for tables where nothing else tells us where they end */
+const MAX_ENTRIES: u64 = 0x80;
+
+/* Looks at the instructions leading up to an access indexed by "index" ('X' or 'Y') for the check that
+   keeps the index in range, and returns the byte offset the index stays below. The register is followed
+   back through TAX/TAY/TXA/TYA, a compare only counts with a BCC/BCS right after it, and an AND only with
+   a small 2^k-1 mask (AND #$00FF just clears the high byte). */
+pub fn index_limit(body: &[&Code], index: char) -> Option<u64> {
+    let mut register = index;
+    let mut doubled = false;
+    let mut next: Option<&Code> = None;
+    for c in body.iter().rev().take(12) {
+        let imm = match (&c.opcode.addr_mode, &c.arg) {
+            (AddrMode::Immediate, ArgType::Address(a)) => Some(*a),
+            _ => None
+        };
+        let checked = next.map(|n| matches!(n.opcode.name, "BCC" | "BCS")).unwrap_or(false);
+        next = Some(c);
+
+        match (c.opcode.name, register, imm) {
+            ("TAX", 'X', _) | ("TAY", 'Y', _) => register = 'A',
+            ("TXA", 'A', _) => register = 'X',
+            ("TYA", 'A', _) => register = 'Y',
+            ("ASL", 'A', None) if c.opcode.addr_mode == AddrMode::Implied => doubled = true,
+            ("CMP", 'A', Some(n)) | ("CPX", 'X', Some(n)) | ("CPY", 'Y', Some(n)) if n > 0 && checked =>
+                return Some(if doubled { n * 2 } else { n }),
+            ("AND", 'A', Some(mask)) if mask < 0xFF && (mask + 1).is_power_of_two() =>
+                return Some(if doubled { mask * 2 + 1 } else { mask + 1 }),
+            ("LDA", 'A', _) | ("PLA", 'A', _) | ("LDX", 'X', _) | ("PLX", 'X', _) | ("LDY", 'Y', _) | ("PLY", 'Y', _) => return None,
+            _ if matches!(c.opcode.name, "RTS" | "RTL" | "JMP" | "JML" | "BRA" | "BRL") => return None,
+            _ => ()
+        }
+    }
+    None
+}
+
+/* Works out how far the tables behind JMP (addr,X) and JSR (addr,X) reach and returns them as
+   pointer overrides, so their entries get labelled and written as pointers like hand-made ones */
+pub fn discover(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<Override> {
+    let mut data_bytes: HashMap<u64, u8> = HashMap::new();
+    let mut code_addrs: HashSet<u64> = HashSet::new();
+    let mut code: Vec<&Code> = Vec::new();
+
+    for line in lines.values().flatten() {
+        match line {
+            Line::Data(d) => {
+                data_bytes.extend(d.bytes().into_iter().enumerate().map(|(i, b)| (d.address + i as u64, b)));
+            },
+            Line::Code(c) => {
+                code_addrs.insert(c.address);
+                code.push(c);
+            },
+            Line::Comment(_) | Line::Binary(_) | Line::Apu(_) => ()
+        }
+    }
+
+    let labels = LABELS.lock().unwrap();
+    let mut overrides = Vec::new();
+    let mut seen: HashSet<u64> = HashSet::new();
+
+    for (i, c) in code.iter().enumerate() {
+        let table_addr = match (&c.opcode.addr_mode, &c.arg) {
+            (AddrMode::AbsoluteIndexedIndirect, ArgType::Address(a)) => (c.address & 0xFF0000) | (a & 0xFFFF),
+            _ => continue
+        };
+
+        if !seen.insert(table_addr) || config.get_override(table_addr).is_some() {
+            continue;
+        }
+
+        /* The index check only caps the table, every entry still has to point at code */
+        let bound = index_limit(&code[i.saturating_sub(12)..i], 'X').map(|limit| limit.div_ceil(2));
+        let mut count = 0;
+        while count < bound.unwrap_or(MAX_ENTRIES).min(MAX_ENTRIES) {
+            let entry_addr = table_addr + count * 2;
+            let word = match (data_bytes.get(&entry_addr), data_bytes.get(&(entry_addr + 1))) {
+                (Some(lo), Some(hi)) => ((*hi as u64) << 8) | *lo as u64,
+                _ => break
+            };
+
+            if count > 0 && (labels.contains_key(&entry_addr) || labels.contains_key(&(entry_addr + 1))) {
+                break;
+            }
+
+            if !code_addrs.contains(&((table_addr & 0xFF0000) | word)) {
+                break;
+            }
+
+            count += 1;
+        }
+
+        if count > 0 {
+            overrides.push(Override {
+                addr: OverrideAddr::Range(vec![table_addr, table_addr + count * 2 - 1]),
+                db: Some(table_addr >> 16),
+                _type: Some("Pointer".to_string()),
+                ..Default::default()
+            });
+        }
+    }
+
+    overrides
+}
@@ -3,7 +3,7 @@ use if_chain::if_chain;
 use lazy_static::lazy_static;
 use std::sync::Mutex;
 
-use crate::{code::ArgType, config::Config, data::DataVal, line::Line, opcode::{AddrMode, Opcode}};
+use crate::{code::ArgType, config::Config, data::DataVal, hwregs, line::Line, opcode::{AddrMode, Opcode}, structs};
 
 lazy_static! {
     pub static ref LABELS: Mutex<HashMap<u64, Label>> = Mutex::new(HashMap::new());
@@ -70,21 +70,24 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                         },
                         Opcode { addr_mode: AddrMode::AbsoluteIndexedIndirect, .. } => {
                             /* Anything using this is using a table of pointers (generally) */
-                            let arg_addr = ((c.db as u64) << 16) | (arg_addr & 0xFFFF);
+                            let arg_addr = ((c.operand_bank() as u64) << 16) | (arg_addr & 0xFFFF);
                             let bank = arg_addr >> 16;
                             let low_addr = arg_addr & 0xFFFF_u64;
                             let (label_addr, prefix) = match low_addr {
-                                0x00..=0xFF if (bank < 0x70 || bank > 0x7F) || bank == 0x7E => (0, ""), // Don't label DP for now
-                                0x100..=0x1FFF if (bank < 0x70 || bank > 0x7F) || bank == 0x7E => (0x7E0000 | (low_addr & 0xFFFF), "LORAM_PTR"),
-                                0x2000..=0x7FFF if bank < 0x40 || bank >= 0x80 => ((low_addr & 0xFFFF), "HW_PTR"),
+                                0x00..=0xFF if !(0x70..=0x7F).contains(&bank) || bank == 0x7E => (0, ""), // Don't label DP for now
+                                0x100..=0x1FFF if !(0x70..=0x7F).contains(&bank) || bank == 0x7E => (0x7E0000 | (low_addr & 0xFFFF), "LORAM_PTR"),
+                                0x2000..=0x7FFF if !(0x40..0x80).contains(&bank) => (low_addr & 0xFFFF, "HW_PTR"),
                                 _ if bank == 0x7E || bank == 0x7F => (arg_addr, "WRAM_PTR"),
-                                _ if bank >= 0x70 && bank < 0x7E => (arg_addr, "SRAM_PTR"),
+                                _ if (0x70..0x7E).contains(&bank) => (arg_addr, "SRAM_PTR"),
                                 _ => (arg_addr, "PTR")
                             };
                             if label_addr > 0 {
                                 Some(Label {
                                     address: label_addr,
-                                    name: format!("{}_{:06X}", prefix, label_addr),
+                                    name: match prefix {
+                                        "HW_PTR" => hwregs::name(label_addr).unwrap_or(format!("{}_{:06X}", prefix, label_addr)),
+                                        _ => format!("{}_{:06X}", prefix, label_addr)
+                                    },
                                     label_type: LabelType::PointerTable(0),
                                     assigned: false
                                 })                 
@@ -99,17 +102,20 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                             let bank = arg_addr >> 16;
                             let low_addr = arg_addr & 0xFFFF_u64;
                             let (label_addr, prefix) = match low_addr {
-                                0x00..=0xFF if (bank < 0x70 || bank > 0x7F) || bank == 0x7E => (0, ""), // Don't label DP for now
-                                0x100..=0x1FFF if (bank < 0x70 || bank > 0x7F) || bank == 0x7E => (0x7E0000 | (low_addr & 0xFFFF), "LORAM_TBL"),
-                                0x2000..=0x7FFF if bank < 0x40 || bank >= 0x80 => ((low_addr & 0xFFFF), "HW_TBL"),
+                                0x00..=0xFF if !(0x70..=0x7F).contains(&bank) || bank == 0x7E => (0, ""), // Don't label DP for now
+                                0x100..=0x1FFF if !(0x70..=0x7F).contains(&bank) || bank == 0x7E => (0x7E0000 | (low_addr & 0xFFFF), "LORAM_TBL"),
+                                0x2000..=0x7FFF if !(0x40..0x80).contains(&bank) => (low_addr & 0xFFFF, "HW_TBL"),
                                 _ if bank == 0x7E || bank == 0x7F => (arg_addr, "WRAM_TBL"),
-                                _ if bank >= 0x70 && bank < 0x7E => (arg_addr, "SRAM_TBL"),
+                                _ if (0x70..0x7E).contains(&bank) => (arg_addr, "SRAM_TBL"),
                                 _ => (arg_addr, "TBL")
                             };
                             if label_addr > 0 {
                                 Some(Label {
                                     address: label_addr,
-                                    name: format!("{}_{:06X}", prefix, label_addr),
+                                    name: match prefix {
+                                        "HW_TBL" => hwregs::name(label_addr).unwrap_or(format!("{}_{:06X}", prefix, label_addr)),
+                                        _ => format!("{}_{:06X}", prefix, label_addr)
+                                    },
                                     label_type: LabelType::DataTable(0),
                                     assigned: false
                                 })                 
@@ -145,7 +151,7 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                         },
                         Opcode { addr_mode: AddrMode::Relative, .. } => {
                             /* Branches */
-                            let label_addr = ((*addr as i64) + 2 + (((arg_addr & 0xFF) as i8)) as i64) as u64;
+                            let label_addr = ((*addr as i64) + 2 + ((arg_addr & 0xFF) as i8) as i64) as u64;
                             Some(Label {
                                 address: label_addr,
                                 name: format!("BRA_{:06X}", label_addr),
@@ -155,15 +161,14 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                         },
                         Opcode { addr_mode: AddrMode::Absolute, .. } |
                         Opcode { addr_mode: AddrMode::AbsoluteLong, .. } => {
-                            let arg_addr = if c.opcode.addr_mode != AddrMode::AbsoluteLong { ((c.db as u64) << 16) | (arg_addr & 0xFFFF) } else { arg_addr };
+                            let arg_addr = if c.opcode.addr_mode != AddrMode::AbsoluteLong { ((c.operand_bank() as u64) << 16) | (arg_addr & 0xFFFF) } else { arg_addr };
                             let bank = arg_addr >> 16;
                             let low_addr = arg_addr & 0xFFFF_u64;
                             let (label_addr, prefix) = match low_addr {
-                                0x00..=0xFF if (bank < 0x70 || bank > 0x7F) || bank == 0x7E => (0, ""), // Don't label DP for now
-                                0x100..=0x1FFF if (bank < 0x70 || bank > 0x7F) || bank == 0x7E => (0x7E0000 | (low_addr & 0xFFFF), "LORAM"),
-                                0x2000..=0x7FFF if bank < 0x40 || bank >= 0x80 => ((low_addr & 0xFFFF), "HWREG"),
+                                0x00..=0x1FFF if !(0x70..=0x7F).contains(&bank) || bank == 0x7E => (0x7E0000 | (low_addr & 0xFFFF), "LORAM"),
+                                0x2000..=0x7FFF if !(0x40..0x80).contains(&bank) => (low_addr & 0xFFFF, "HWREG"),
                                 _ if bank == 0x7E || bank == 0x7F => (arg_addr, "WRAM"),
-                                _ if bank >= 0x70 && bank < 0x7E => (arg_addr, "SRAM"),
+                                _ if (0x70..0x7E).contains(&bank) => (arg_addr, "SRAM"),
                                 _ if c.opcode.name == "PEA" => (arg_addr + 1, "SUB"),
                                 _ => (arg_addr, "DAT")
                             };
@@ -171,7 +176,10 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                             if label_addr > 0 {
                                 Some(Label {
                                     address: label_addr,
-                                    name: format!("{}_{:06X}", prefix, label_addr),
+                                    name: match prefix {
+                                        "HWREG" => hwregs::name(label_addr).unwrap_or(format!("{}_{:06X}", prefix, label_addr)),
+                                        _ => format!("{}_{:06X}", prefix, label_addr)
+                                    },
                                     label_type: if prefix != "SUB" { LabelType::Data } else { LabelType::Subroutine }, 
                                     assigned: false
                                 })
@@ -179,6 +187,19 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                                 None
                             }
                         },
+                        Opcode { addr_mode, .. } if addr_mode.is_direct() => {
+                            /* Direct page operands, resolved through the tracked D register */
+                            let suffix = if addr_mode.is_indirect() { "_PTR" } else { "" };
+                            c.direct_page_addr().map(|label_addr| Label {
+                                address: label_addr,
+                                name: match hwregs::name(label_addr).filter(|_| (0x2000..=0x7FFF).contains(&label_addr)) {
+                                    Some(name) if suffix.is_empty() => name,
+                                    _ => format!("{}{}_{:06X}", match label_addr { 0x2000..=0x7FFF => "HWREG", 0x7E0000..=0x7EFFFF => "LORAM", _ => "DAT" }, suffix, label_addr)
+                                },
+                                label_type: LabelType::Data,
+                                assigned: false
+                            })
+                        },
                         _ => None
                     },
                     _ => None
@@ -214,38 +235,33 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                             }
                         }
 
-                        /* Handle struct overrides */
-                        if_chain! {
-                            if let Some(ov) = config.get_override(cur_pc);
-                            if let Some(t) = &ov._type;
-                            if t == "Struct";
-                            then {
-                                if let Some(st) = config.structs.iter().find(|s| &s.name == ov._struct.as_ref().unwrap_or(&"".to_string())) {                                    
-                                    let last_field = &st.fields[st.fields.len() - 1];
-                                    let st_len = last_field.offset + last_field.length;
-                                    let cur_offset = cur_pc - data.address;
-                                    let cur_st_offset = cur_offset % st_len;
-                                    let field = &st.fields.iter().find(|f| f.offset == cur_st_offset).unwrap();
-                                    if field._type == "Pointer" {
-                                        let db = field.db.unwrap_or(cur_pc >> 16);                                    
-                                        let label_addr = if field.length < 3 { (d.as_u64() & 0xFFFF_u64) | (db << 16) } else { d.as_u64() };
-                                        if (label_addr & 0xFFFF) >= 0x8000 {
-                                            labels.entry(label_addr).or_insert(Label { 
-                                                address: label_addr, 
-                                                name: format!("{}_{:04X}", field.name, label_addr & 0xFFFF_u64), label_type: LabelType::Subroutine, assigned: false });
-                                        }
-                                    }
-                                    if cur_st_offset == 0 {
-                                        labels.entry(cur_pc)
-                                            .and_modify(|l| l.name = format!("{}_{:04X}", st.name, cur_pc & 0xFFFF_u64))
-                                            .or_insert(Label {
-                                                address: cur_pc,
-                                                name: format!("{}_{:04X}", st.name, cur_pc & 0xFFFF_u64),
-                                                label_type: LabelType::DataTable(0),
-                                                assigned: false
-                                            });
-                                    }
-                                }
+                        /* Offset tables point relative to their base, both ends need a label */
+                        if let Some((target, base)) = data.offset_target(cur_pc, d, config) {
+                            labels.entry(base).or_insert(Label {
+                                address: base, name: format!("DAT_{:06X}", base), label_type: LabelType::Data, assigned: false });
+                            labels.entry(target).or_insert(Label {
+                                address: target, name: format!("DAT_{:06X}", target), label_type: LabelType::Data, assigned: false });
+                        }
+
+                        /* Handle struct fields */
+                        if let Some(tag) = config.struct_tags.get(&cur_pc) {
+                            if let Some(label_addr) = structs::pointer_target(tag, cur_pc, d.as_u64()) {
+                                let name = match &tag.leaf.target {
+                                    Some(target) => format!("{}_{:04X}", target, label_addr & 0xFFFF_u64),
+                                    None => format!("{}_{:04X}", tag.leaf.path.replace(['.', '['], "_").replace(']', ""), label_addr & 0xFFFF_u64)
+                                };
+                                let label_type = if tag.leaf.target.is_some() { LabelType::DataTable(0) } else { LabelType::Subroutine };
+                                labels.entry(label_addr).or_insert(Label { address: label_addr, name, label_type, assigned: false });
+                            }
+                            if tag.record == cur_pc && tag.labelled && !tag.is_terminator() {
+                                labels.entry(cur_pc)
+                                    .and_modify(|l| l.name = format!("{}_{:04X}", tag.st, cur_pc & 0xFFFF_u64))
+                                    .or_insert(Label {
+                                        address: cur_pc,
+                                        name: format!("{}_{:04X}", tag.st, cur_pc & 0xFFFF_u64),
+                                        label_type: LabelType::DataTable(0),
+                                        assigned: false
+                                    });
                             }
                         }
 
@@ -271,6 +287,37 @@ pub fn generate_labels(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) {
                     labels.entry(label.address).or_insert(label);
                 }
             }
+
+            /* Label the targets of indirect operands using the log's effective address. With an index added
+               it's only where one run happened to go, those keep it as a comment unless it's a jump. */
+            if_chain! {
+                if let Line::Code(c) = addr_line;
+                if c.opcode.addr_mode.is_indirect();
+                if matches!(c.opcode.name, "JMP" | "JML" | "JSR") || matches!(c.opcode.addr_mode,
+                    AddrMode::DirectIndirect | AddrMode::DirectIndirectLong | AddrMode::AbsoluteIndirect | AddrMode::AbsoluteIndirectLong);
+                if let Some(target) = c.effective;
+                then {
+                    let bank = target >> 16;
+                    let low_addr = target & 0xFFFF_u64;
+                    let (prefix, label_type) = match low_addr {
+                        _ if matches!(c.opcode.name, "JMP" | "JML" | "JSR") => ("SUB", LabelType::Subroutine),
+                        0x00..=0x1FFF if bank == 0x7E => ("LORAM", LabelType::Data),
+                        0x2000..=0x7FFF if !(0x40..0x80).contains(&bank) => ("", LabelType::Data),
+                        _ if bank == 0x7E || bank == 0x7F => ("WRAM", LabelType::Data),
+                        _ if (0x70..0x7E).contains(&bank) => ("SRAM", LabelType::Data),
+                        _ => ("DAT", LabelType::Data)
+                    };
+
+                    if !prefix.is_empty() {
+                        labels.entry(target).or_insert(Label {
+                            address: target,
+                            name: format!("{}_{:06X}", prefix, target),
+                            label_type,
+                            assigned: false
+                        });
+                    }
+                }
+            }
         }
     }
 }
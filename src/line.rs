@@ -1,6 +1,8 @@
 use crate::config::Config;
 use crate::data::{Data, DataVal};
 use crate::code::{Code, ArgType};
+use crate::apu::Chain;
+use crate::incbin::Binary;
 use crate::opcode::{OPCODES};
 use regex::Regex;
 use lazy_static::lazy_static;
@@ -27,7 +29,9 @@ pub enum Line
 {
     Comment(String),
     Data(Data),
-    Code(Code)
+    Code(Code),
+    Binary(Binary),
+    Apu(Chain)
 }
 
 impl Line {
@@ -35,7 +39,9 @@ impl Line {
         match self {
             Line::Comment(s) => s.to_string(),
             Line::Data(d) => d.to_string(config),
-            Line::Code(c) => c.to_string(config)
+            Line::Code(c) => c.to_string(config),
+            Line::Binary(b) => b.to_string(),
+            Line::Apu(a) => a.to_string()
         }
     }
 }
@@ -56,7 +62,7 @@ impl Line {
             let address: u64 = u64::from_str_radix(&raw_addr.replace(":", ""), 16).unwrap();
             let opcodes: Vec<u8> = raw_opcode.trim().split(' ').map(|o| u8::from_str_radix(o, 16).unwrap()).collect();
             let opcode = &OPCODES[&opcodes[0]];
-            let arg = ArgType::BlockMove(opcodes[1] as u8, opcodes[2] as u8);
+            let arg = ArgType::BlockMove(opcodes[1], opcodes[2]);
             
             let code = Code {
                 address,
@@ -64,6 +70,11 @@ impl Line {
                 arg,
                 length: 3,
                 db: (address >> 16) as u8,
+                dp: Some(0),
+                log_db: None,
+                effective: None,
+                acc_8bit: None,
+                index_8bit: None,
                 comment: comment.map(|c| c.as_str()[1..].to_owned())
             };
 
@@ -71,7 +82,7 @@ impl Line {
 
         }
         else if let Some(cap) = CODE_REGEX.captures(line) {
-            let (raw_addr, raw_opcode, _op_name, _op_arg, op_db, comment) = (&cap[1], &cap[2], &cap[4], &cap[5], cap.get(8), cap.get(10));
+            let (raw_addr, raw_opcode, _op_name, _op_arg, op_db, op_effective, comment) = (&cap[1], &cap[2], &cap[4], &cap[5], cap.get(8), cap.get(9), cap.get(10));
             let address: u64 = u64::from_str_radix(&raw_addr.replace(":", ""), 16).unwrap();
             let opcodes: Vec<u8> = raw_opcode.trim().split(' ').map(|o| u8::from_str_radix(o, 16).unwrap()).collect();
             let mut arg_addr: u64 = 0;
@@ -105,6 +116,22 @@ impl Line {
                 }                    
             };
 
+            /* Low RAM and the registers are in every bank the game sets, DB makes no difference there */
+            let log_db = op_db
+                .filter(|db| db.as_str().contains(':') && (arg_addr & 0xFFFF) >= 0x8000)
+                .map(|db| u8::from_str_radix(&db.as_str()[2..4], 16).unwrap());
+
+            /* The log's effective address, a bare 16-bit address is in the current bank */
+            let effective = op_effective.map(|e| {
+                let e = e.as_str().replace(":", "");
+                let target = u64::from_str_radix(&e, 16).unwrap();
+                match (e.len(), target) {
+                    (4, 0..=0x1FFF) => 0x7E0000 | target,
+                    (4, _) => (address & 0xFF0000) | target,
+                    _ => target
+                }
+            });
+
             let comment = comment.map(|c| c.as_str()[1..].to_owned());
 
             let code = Code {
@@ -113,6 +140,11 @@ impl Line {
                 arg,
                 length,
                 db,
+                dp: Some(0),
+                log_db,
+                effective,
+                acc_8bit: None,
+                index_8bit: None,
                 comment: comment.clone()
             };
             
@@ -1,6 +1,7 @@
 use std::{collections::BTreeMap, fs::File, io::{BufRead, BufReader, Write}};
 use regex::Regex;
 use glob::glob;
+use if_chain::if_chain;
 
 mod code;
 mod opcode;
@@ -8,6 +9,27 @@ mod data;
 mod label;
 mod line;
 mod config;
+mod state;
+mod graph;
+mod xref;
+mod jumptable;
+mod tables;
+mod suggest;
+mod audit;
+mod byteref;
+mod structs;
+mod constants;
+mod hwregs;
+mod dma;
+mod inline;
+mod bytecode;
+mod incbin;
+mod compression;
+mod palette;
+mod tiles;
+mod spc700;
+mod apu;
+mod registers;
 
 use data::{Data};
 use code::{Code, ArgType};
@@ -19,9 +41,31 @@ use crate::label::LabelType;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
+    let command = std::env::args().nth(1);
     let filename_regex = Regex::new(r"Bank \$([0-9A-F]{2})(\.\.\$([0-9A-F]{2})|)").unwrap();
     let mut bank_groups: Vec<(u8, u8)> = Vec::new();
-    let config = config::Config::load("./config/");
+    let mut config = config::Config::load("./config/");
+
+    /* Build step: compress the edited assets again before assembling, nothing else is needed for that */
+    if command.as_deref() == Some("compress") {
+        std::fs::create_dir_all("./reports").unwrap();
+        compression::rebuild("./asm/data", "./reports/compression_build.txt");
+        return Ok(());
+    }
+
+    /* Build step: bring edited .pal and .gpl files back into the palettes */
+    if command.as_deref() == Some("palettes") {
+        std::fs::create_dir_all("./reports").unwrap();
+        palette::import("./asm/palettes", "./reports/palettes_build.txt");
+        return Ok(());
+    }
+
+    /* Build step: make the tiles from edited PNGs again */
+    if command.as_deref() == Some("tiles") {
+        std::fs::create_dir_all("./reports").unwrap();
+        tiles::import("./asm/gfx", "./reports/tiles_build.txt");
+        return Ok(());
+    }
 
     let mut lines: BTreeMap<u64, Vec<Line>> = BTreeMap::new();
     let filenames = glob("./logs/*.asm").unwrap();
@@ -48,9 +92,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut cur_addr = 0x008000 | ((bank_group.0 as u64) << 16);
         
         /* Parse the full file into data */
-        for (addr, line) in reader.lines().flatten().map(|l| Line::parse(&l, &config)) {
+        for (addr, line) in reader.lines().map_while(Result::ok).map(|l| Line::parse(&l, &config)) {
             cur_addr = addr.unwrap_or(cur_addr);
-            lines.entry(cur_addr).or_insert_with(Vec::new).push(line);
+            lines.entry(cur_addr).or_default().push(line);
         }
     }
     
@@ -80,6 +124,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             length: c.length,
                             opcode: c.opcode,
                             db: *bank as u8,
+                            dp: c.dp,
+                            log_db: None,
+                            effective: c.effective.map(|e| if e >> 16 == 0xA0 { bank << 16 | e & 0xFFFF_u64 } else { e }),
+                            acc_8bit: c.acc_8bit,
+                            index_8bit: c.index_8bit,
                             arg: new_arg
                         })
                     },
@@ -91,7 +140,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             data: d.data.clone()
                         })
                     },
-                    Line::Comment(c) => Line::Comment(c.to_string())
+                    Line::Comment(c) => Line::Comment(c.to_string()),
+                    Line::Binary(b) => Line::Binary(b.clone()),
+                    Line::Apu(a) => Line::Apu(a.clone())
                 };
 
                 new_lines.push(new_line);
@@ -101,11 +152,138 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    std::fs::create_dir_all("./reports").unwrap();
+
+    /* Arguments that follow calls to subroutines with an inline layout become data */
+    let mut inline_args = inline::convert_args(&mut lines, &config);
+    config.overrides.append(&mut inline_args);
+
+    /* Instruction lists get decoded first, the structs they point at are laid over the data along with the rest.
+       Structs can lead to more lists (a room header to its states), both go again until none turn up. */
+    let (instruction_tags, bytecode_errors, struct_tags, struct_errors) = loop {
+        let (instruction_tags, mut struct_roots, bytecode_errors) = bytecode::resolve(&lines, &config);
+        let roots_start = config.overrides.len();
+        config.overrides.append(&mut struct_roots);
+
+        let (struct_tags, struct_errors, mut lists) = structs::resolve(&lines, &config);
+        lists.retain(|l| !config.overrides.contains(l));
+        if lists.is_empty() {
+            break (instruction_tags, bytecode_errors, struct_tags, struct_errors);
+        }
+        config.overrides.truncate(roots_start);
+        config.overrides.append(&mut lists);
+    };
+
+    /* Give every record its own line, problems end up in a report */
+    config.struct_tags = struct_tags;
+    for (addr, tag) in instruction_tags {
+        config.struct_tags.entry(addr).or_insert(tag);
+    }
+    structs::split_records(&mut lines, &config);
+    palette::split_rows(&mut lines, &config);
+    let mut report_file = File::create("./reports/struct_errors.txt").unwrap();
+    for e in &struct_errors {
+        let _ = writeln!(report_file, "{}", e);
+    }
+    let mut report_file = File::create("./reports/bytecode_errors.txt").unwrap();
+    for e in &bytecode_errors {
+        let _ = writeln!(report_file, "{}", e);
+    }
+
+    /* Follow the direct page and data bank registers so operands resolve to their real address */
+    let db_mismatches = state::track_registers(&mut lines, &config);
+    let mut report_file = File::create("./reports/db_mismatches.txt").unwrap();
+    for m in &db_mismatches {
+        let _ = writeln!(report_file, "${:06X}: log has DB ${:02X}, inferred DB ${:02X}", m.address, m.log_db, m.inferred_db);
+    }
+
     /* Autogenerate labels */
     label::generate_labels(&lines, &config);
 
+    /* Jump tables found in the code become pointer overrides, their entries need another labelling pass */
+    let mut jump_tables = jumptable::discover(&lines, &config);
+    config.overrides.append(&mut jump_tables);
+    label::generate_labels(&lines, &config);
+
+    /* DMA and HDMA setups get their source data labelled and written symbolically */
+    let transfers = dma::discover(&lines, &config);
+    let mut dma_overrides = dma::overrides(&transfers, &config);
+    if !dma_overrides.is_empty() {
+        config.overrides.append(&mut dma_overrides);
+        label::generate_labels(&lines, &config);
+    }
+    dma::name_labels(&transfers);
+    bytecode::name_labels(&config);
+
+    /* Size the generated tables from how the code indexes them, pointer tables get their entries labelled */
+    let table_info = tables::infer_lengths(&lines, &config);
+    tables::write_suggestions(&table_info, "./reports/suggested_labels.yaml");
+    let mut table_overrides = tables::overrides(&lines, &table_info, &config);
+    if !table_overrides.is_empty() {
+        config.overrides.append(&mut table_overrides);
+        label::generate_labels(&lines, &config);
+    }
+
+    /* Values that only hold part of an address get written as expressions on a label */
+    config.byte_refs = byteref::resolve(&lines, &config);
+
+    /* Immediates stored to or compared with addresses that have constants get written with them */
+    config.immediate_enums = constants::immediate_enums(&lines, &config);
+
+    /* Known values written to hardware registers get their fields spelled out */
+    hwregs::annotate_writes(&mut lines, &config);
+    dma::annotate(&mut lines, &transfers);
+
+    if command.as_deref() == Some("suggest") {
+        let suggestions = suggest::suggest_overrides(&lines, &config);
+        suggest::print_suggestions(&suggestions);
+        return Ok(());
+    }
+
+    if command.as_deref() == Some("graph") {
+        let graphs = graph::build(&lines, &config);
+        graph::export(&graphs, "./graphs");
+        return Ok(());
+    }
+
+    /* Cross references for every label, as a report and optionally as comments above the labels */
+    let xrefs = xref::build(&lines, &config);
+    xref::write_report(&xrefs, "./reports/xref.txt");
+    let xref_comments = xref::XrefComments::new(&xrefs);
+
+    /* Literal values that are really pointers and would break relocation */
+    let literals = audit::relocation_audit(&lines, &config);
+    audit::write_report(&literals, "./reports/relocation_audit.txt");
+
+    constants::write_defines(&config, "./asm/constants.asm");
+
+    /* Uploads to the APU become their blocks, with the SPC700 code in them disassembled */
+    let (chains, apu_errors) = apu::extract(&mut lines, &config);
+    apu::write_report(&chains, &apu_errors, "./reports/apu_blocks.txt");
+
+    /* Tiles go out as PNG sheets, before the palettes they use are moved out of the lines */
+    let tiles_errors = tiles::extract(&mut lines, &config, "./asm/gfx");
+    let mut report_file = File::create("./reports/tiles.txt").unwrap();
+    for e in &tiles_errors {
+        let _ = writeln!(report_file, "{}", e);
+    }
+
+    /* Palettes go to files of their own, exported for editing */
+    palette::extract(&mut lines, &config, "./asm/palettes");
+
+    /* Compressed data goes out decompressed, with a check that it compresses back the same */
+    let compressed = compression::extract(&mut lines, &config, "./asm/data");
+    compression::write_report(&compressed, "./reports/compression.txt");
+
+    /* Big runs of plain data go into files of their own */
+    incbin::extract(&mut lines, &config, "./asm/data");
+
     let mut output_file = File::create("./asm/main.asm").unwrap();
+    for pack in &config.packs {
+        let _ = writeln!(output_file, "; {} pack {}", pack.name, pack.version);
+    }
     let _ = writeln!(output_file, "lorom");
+    let _ = writeln!(output_file, "incsrc constants.asm");
     let _ = writeln!(output_file, "incsrc labels.asm");
     for group in 0x80..0xE0 {
         let _ = writeln!(output_file, "incsrc bank_{:02X}.asm", group);
@@ -118,7 +296,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         if bank != cur_bank {
             let _ = writeln!(output_file, "check bankcross on");
 
-            let first_entry = lines.iter().find(|(k, v)| **k >= (((bank as u64) << 16) | 0x8000) && v.iter().any(|l| matches!(l, Line::Code(_) | Line::Data(_)))).unwrap();
+            let first_entry = lines.iter().find(|(k, v)| **k >= (((bank as u64) << 16) | 0x8000) && v.iter().any(|l| matches!(l, Line::Code(_) | Line::Data(_) | Line::Binary(_) | Line::Apu(_)))).unwrap();
             let first_address = if (first_entry.0 >> 16) == bank as u64 { first_entry.0 } else { addr };
 
             cur_bank = bank;
@@ -131,8 +309,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                otherwise it will deadlock. */
             let mut labels = LABELS.lock().unwrap();
             if labels.contains_key(addr) {
+                if_chain! {
+                    if config.options.xref_comments;
+                    if let Some(comment) = xref_comments.get(*addr);
+                    then {
+                        let _ = writeln!(output_file, "{}", comment);
+                    }
+                }
                 let _ = writeln!(output_file, "{}{}", labels[addr].name, if labels[addr].name.starts_with(".") { "" } else { ":" });
-                let mut label = labels.get_mut(addr).unwrap();
+                let label = labels.get_mut(addr).unwrap();
                 label.assigned = true;
             }
         }
@@ -121,7 +121,6 @@ lazy_static! {
         0x83 => Opcode::new(0x83, "STA", AddrMode::StackRelative),
         0x85 => Opcode::new(0x85, "STA", AddrMode::Direct),
         0x87 => Opcode::new(0x87, "STA", AddrMode::DirectIndirectLong),
-        0x89 => Opcode::new(0x89, "STA", AddrMode::Immediate),
         0x8D => Opcode::new(0x8D, "STA", AddrMode::Absolute),
         0x8F => Opcode::new(0x8F, "STA", AddrMode::AbsoluteLong),
         0x91 => Opcode::new(0x91, "STA", AddrMode::DirectIndirectIndexed),
@@ -161,7 +160,7 @@ lazy_static! {
         0x2C => Opcode::new(0x2C, "BIT", AddrMode::Absolute),
         0x34 => Opcode::new(0x34, "BIT", AddrMode::DirectIndexedX),
         0x3C => Opcode::new(0x3C, "BIT", AddrMode::AbsoluteIndexedX),
-        0x89 => Opcode::new(0x24, "BIT", AddrMode::Immediate),
+        0x89 => Opcode::new(0x89, "BIT", AddrMode::Immediate),
 
         0xE0 => Opcode::new(0xE0, "CPX", AddrMode::Immediate),
         0xE4 => Opcode::new(0xE4, "CPX", AddrMode::Direct),
@@ -271,7 +270,7 @@ lazy_static! {
         0xE2 => Opcode::new(0xE2, "SEP", AddrMode::ImmediateByte),
 
         0x40 => Opcode::new(0x40, "RTI", AddrMode::Implied),
-        0x6B => Opcode::new(0x4B, "RTL", AddrMode::Implied),
+        0x6B => Opcode::new(0x6B, "RTL", AddrMode::Implied),
         0x60 => Opcode::new(0x60, "RTS", AddrMode::Implied),
 
         0x38 => Opcode::new(0x38, "SEC", AddrMode::Implied),
@@ -303,7 +302,7 @@ lazy_static! {
         0x42 => Opcode::new(0x42, "WDM", AddrMode::Immediate),
         
         0xEB => Opcode::new(0xEB, "XBA", AddrMode::Implied),
-        0xFB => Opcode::new(0xDB, "XCE", AddrMode::Implied)
+        0xFB => Opcode::new(0xFB, "XCE", AddrMode::Implied)
     };
 }
 
@@ -348,3 +347,21 @@ impl Opcode {
         Self { opcode, name, addr_mode }
     }
 }
+
+impl AddrMode {
+    /* Modes whose one-byte operand is an offset from the direct page register */
+    pub fn is_direct(&self) -> bool {
+        matches!(self,
+            AddrMode::Direct | AddrMode::DirectIndexedX | AddrMode::DirectIndexedY |
+            AddrMode::DirectIndirect | AddrMode::DirectIndexedIndirect | AddrMode::DirectIndirectIndexed |
+            AddrMode::DirectIndirectLong | AddrMode::DirectIndirectIndexedLong)
+    }
+
+    /* Modes that read the final address through a pointer */
+    pub fn is_indirect(&self) -> bool {
+        matches!(self,
+            AddrMode::DirectIndirect | AddrMode::DirectIndexedIndirect | AddrMode::DirectIndirectIndexed |
+            AddrMode::DirectIndirectLong | AddrMode::DirectIndirectIndexedLong |
+            AddrMode::AbsoluteIndirect | AddrMode::AbsoluteIndirectLong | AddrMode::AbsoluteIndexedIndirect)
+    }
+}
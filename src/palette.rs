@@ -0,0 +1,187 @@
+use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
+
+use crate::{config::{Config, Override, OverrideAddr}, data::{Data, DataVal}, incbin::{self, Binary}, line::Line};
+
+/* Colours on a line, as many as a sub-palette has */
+const ROW: usize = 16;
+
+/* BGR555 to 8 bits per channel, the low bits repeat the high ones so 31 comes out as 255 */
+pub fn to_rgb(color: u16) -> [u8; 3] {
+    let channel = |shift: u16| {
+        let v = ((color >> shift) & 0x1F) as u8;
+        (v << 3) | (v >> 2)
+    };
+    [channel(0), channel(5), channel(10)]
+}
+
+pub fn from_rgb(rgb: [u8; 3]) -> u16 {
+    (rgb[0] >> 3) as u16 | (((rgb[1] >> 3) as u16) << 5) | (((rgb[2] >> 3) as u16) << 10)
+}
+
+/* The colours of the words in a line, for the comment next to it */
+pub fn comment(data: &[DataVal]) -> String {
+    data.iter()
+        .filter_map(|d| match d {
+            DataVal::DW(w) => Some(to_rgb(*w)),
+            _ => None
+        })
+        .map(|[r, g, b]| format!("#{:02X}{:02X}{:02X}", r, g, b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn is_palette(ov: &Override) -> bool {
+    ov._type.as_deref() == Some("Palette")
+}
+
+/* Where a Palette override starts and how many bytes it covers, a single address is one sub-palette */
+pub fn region(ov: &Override) -> (u64, u64) {
+    match &ov.addr {
+        OverrideAddr::Range(r) => (r[0], r[1] - r[0] + 1),
+        OverrideAddr::Address(a) => (*a, ROW as u64 * 2)
+    }
+}
+
+fn colors(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|c| c[0] as u16 | ((c[1] as u16) << 8)).collect()
+}
+
+/* The lines of a palette file, the same as the bank files would have them */
+fn text(colors: &[u16]) -> String {
+    colors.chunks(ROW)
+        .map(|row| {
+            let values: Vec<DataVal> = row.iter().map(|c| DataVal::DW(*c)).collect();
+            let words: Vec<String> = row.iter().map(|c| format!("${:04X}", c)).collect();
+            format!("    dw {} ; {}\n", words.join(","), comment(&values))
+        })
+        .collect()
+}
+
+/* Gives every sub-palette of the Palette overrides a line of its own, so the colours can be written next to it */
+pub fn split_rows(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config) {
+    for ov in config.overrides.iter().filter(|ov| is_palette(ov)) {
+        let (start, length) = region(ov);
+        let bytes = incbin::read_bytes(lines, start, length);
+        if (bytes.len() as u64) < length {
+            /* Not all of it is in the log, leave it */
+            continue;
+        }
+
+        let mut rows: Vec<Line> = bytes.chunks(ROW * 2).enumerate()
+            .map(|(i, row)| Line::Data(Data {
+                address: start + (i * ROW * 2) as u64,
+                data: colors(row).into_iter().map(DataVal::DW).collect(),
+                comment: None
+            }))
+            .collect();
+        if length % 2 == 1 {
+            rows.push(Line::Data(Data { address: start + length - 1, data: vec![DataVal::DB(bytes[bytes.len() - 1])], comment: None }));
+        }
+        incbin::replace(lines, start, start + length, rows);
+    }
+}
+
+fn write_pal(path: &str, colors: &[u16]) {
+    let mut output_file = File::create(path).unwrap();
+    let _ = write!(output_file, "JASC-PAL\r\n0100\r\n{}\r\n", colors.len());
+    for [r, g, b] in colors.iter().map(|c| to_rgb(*c)) {
+        let _ = write!(output_file, "{} {} {}\r\n", r, g, b);
+    }
+}
+
+fn write_gpl(path: &str, name: &str, colors: &[u16]) {
+    let mut output_file = File::create(path).unwrap();
+    let _ = writeln!(output_file, "GIMP Palette\nName: {}\nColumns: {}\n#", name, ROW);
+    for [r, g, b] in colors.iter().map(|c| to_rgb(*c)) {
+        let _ = writeln!(output_file, "{:3} {:3} {:3}\t#{:02X}{:02X}{:02X}", r, g, b, r, g, b);
+    }
+}
+
+/* The colours of a .pal or .gpl file, every line that starts with three numbers is one */
+fn read_colors(path: &Path) -> Option<Vec<u16>> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let header = if path.extension()? == "pal" { 3 } else { 0 };
+    let colors: Vec<u16> = text.lines().skip(header)
+        .filter_map(|l| {
+            let channels: Vec<u8> = l.split_whitespace().take(3).map_while(|v| v.parse().ok()).collect();
+            match channels[..] {
+                [r, g, b] => Some(from_rgb([r, g, b])),
+                _ => None
+            }
+        })
+        .collect();
+    Some(colors)
+}
+
+/* The words on the dw lines of a palette's .asm file */
+fn read_asm(path: &Path) -> Vec<u16> {
+    let text = std::fs::read_to_string(path).unwrap_or_default();
+    text.lines()
+        .filter_map(|l| l.trim().strip_prefix("dw "))
+        .flat_map(|l| l.split(';').next().unwrap_or("").split(','))
+        .filter_map(|v| u16::from_str_radix(v.trim().trim_start_matches('$'), 16).ok())
+        .collect()
+}
+
+/* Writes each Palette override to "<folder>/<label>.asm", which the bank files include, along with the
+   same colours as "<label>.pal" and "<label>.gpl" for editing. The "palettes" command brings edits back. */
+pub fn extract(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config, folder: &str) {
+    for ov in config.overrides.iter().filter(|ov| is_palette(ov)) {
+        let (start, length) = region(ov);
+        let bytes = incbin::read_bytes(lines, start, length);
+        if (bytes.len() as u64) < length || length % 2 == 1 {
+            continue;
+        }
+        let (base, inner) = match incbin::region_labels(start, start + length, "PAL") {
+            Some(l) => l,
+            None => continue
+        };
+
+        std::fs::create_dir_all(folder).unwrap();
+        let name = base.trim_start_matches('.').to_string();
+        let colors = colors(&bytes);
+        let mut output_file = File::create(format!("{}/{}.asm", folder, name)).unwrap();
+        let _ = write!(output_file, "{}", text(&colors));
+        write_pal(&format!("{}/{}.pal", folder, name), &colors);
+        write_gpl(&format!("{}/{}.gpl", folder, name), &name, &colors);
+
+        incbin::replace(lines, start, start + length, vec![Line::Binary(Binary {
+            directive: "incsrc", base, file: format!("palettes/{}.asm", name), labels: inner
+        })]);
+    }
+}
+
+/* The build step: palettes whose .pal and .gpl no longer agree have been edited, the newer of the two
+   is written to the .asm and the other file. Colours can't change count, the data around them would move.
+   Neither file has room for bit 15 of a colour, the one in the .asm is kept. */
+pub fn import(folder: &str, filename: &str) {
+    let mut report_file = File::create(filename).unwrap();
+    let mut files: Vec<_> = glob::glob(&format!("{}/*.asm", folder)).unwrap().flatten().collect();
+    files.sort();
+    for asm_path in files {
+        let name = asm_path.file_stem().unwrap().to_string_lossy().to_string();
+        let (pal_path, gpl_path) = (asm_path.with_extension("pal"), asm_path.with_extension("gpl"));
+        let (Some(pal), Some(gpl)) = (read_colors(&pal_path), read_colors(&gpl_path)) else {
+            continue;
+        };
+        if pal == gpl {
+            let _ = writeln!(report_file, "{}: unchanged", name);
+            continue;
+        }
+
+        let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+        let (colors, from) = if modified(&pal_path) > modified(&gpl_path) { (pal, &pal_path) } else { (gpl, &gpl_path) };
+        let old = read_asm(&asm_path);
+        if colors.len() != old.len() {
+            let _ = writeln!(report_file, "{}: {} has {} colours instead of {}, left as it was", name, from.display(), colors.len(), old.len());
+            continue;
+        }
+        let colors: Vec<u16> = colors.iter().zip(&old).map(|(c, o)| c | (o & 0x8000)).collect();
+
+        let mut output_file = File::create(&asm_path).unwrap();
+        let _ = write!(output_file, "{}", text(&colors));
+        write_pal(&pal_path.to_string_lossy(), &colors);
+        write_gpl(&gpl_path.to_string_lossy(), &name, &colors);
+        let _ = writeln!(report_file, "{}: imported from {}", name, from.display());
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use crate::{code::{ArgType, Code}, opcode::AddrMode};
+
+/* Follows what A, X and Y hold through straight-line code. Everything is forgotten wherever the
+   flow of the code joins up, and whatever an instruction might change is forgotten after it. */
+pub struct Registers<T> {
+    pub values: [Option<T>; 3],
+    branch_targets: HashSet<u64>,
+    next: u64
+}
+
+/* Which register an instruction loads or stores, by name */
+pub fn register(name: &str) -> Option<usize> {
+    match name {
+        "LDA" | "STA" => Some(0),
+        "LDX" | "STX" => Some(1),
+        "LDY" | "STY" => Some(2),
+        _ => None
+    }
+}
+
+/* The registers an instruction changes other than by loading them */
+fn changes(c: &Code) -> &'static [usize] {
+    match c.opcode.name {
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" if c.opcode.addr_mode != AddrMode::Implied => &[],
+        "TXA" | "TYA" | "PLA" | "XBA" | "ADC" | "SBC" | "AND" | "ORA" | "EOR" | "TDC" | "TSC" |
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" => &[0],
+        "TAX" | "TSX" | "TYX" | "PLX" | "INX" | "DEX" => &[1],
+        "TAY" | "TXY" | "PLY" | "INY" | "DEY" => &[2],
+        "JSR" | "JSL" | "JMP" | "JML" | "RTS" | "RTL" | "RTI" | "MVN" | "MVP" | "BRK" | "COP" | "XCE" => &[0, 1, 2],
+        _ => &[]
+    }
+}
+
+impl<T: Copy> Registers<T> {
+    pub fn new(code: &[&Code]) -> Registers<T> {
+        Registers { values: [None; 3], branch_targets: code.iter().filter_map(|c| c.branch_target()).collect(), next: 0 }
+    }
+
+    /* Called before looking at each instruction, true if the instruction doesn't follow on from the one before */
+    pub fn start(&mut self, c: &Code) -> bool {
+        let follows = c.address == self.next;
+        if !follows || self.branch_targets.contains(&c.address) {
+            self.values = [None; 3];
+        }
+        self.next = c.address + 1 + c.length as u64;
+        !follows
+    }
+
+    /* Forgets the registers the instruction changes, loads are left to the caller */
+    pub fn clobber(&mut self, c: &Code) {
+        for r in changes(c) {
+            self.values[*r] = None;
+        }
+    }
+}
+
+/* An immediate loaded into a register, with the instruction that loaded it */
+#[derive(Clone, Copy)]
+pub struct Loaded {
+    pub value: u64,
+    pub size: u64,
+    pub insn: u64
+}
+
+/* What a store writes: a known value, or something unknown over this many bytes at most */
+pub enum Stored {
+    Known(Loaded),
+    Unknown(u64)
+}
+
+/* Steps over an instruction keeping track of the immediates loaded into the registers, and gives back
+   what it writes if it's a store. Stores are as wide as the register at the store, when that width
+   isn't known or is wider than the immediate loaded, what they write isn't known either. */
+pub fn immediates(regs: &mut Registers<Loaded>, c: &Code) -> Option<Stored> {
+    let name = c.opcode.name;
+    let width = |r: usize| match if r == 0 { c.acc_8bit } else { c.index_8bit } {
+        Some(true) => Some(1),
+        Some(false) => Some(2),
+        None => None
+    };
+
+    let (r, value) = match (register(name), name) {
+        (Some(r), _) if name.starts_with("LD") => {
+            regs.values[r] = match (&c.opcode.addr_mode, &c.arg) {
+                (AddrMode::Immediate, ArgType::Address(v)) => Some(Loaded { value: *v, size: c.length as u64, insn: c.address }),
+                _ => None
+            };
+            return None;
+        },
+        (Some(r), _) => (r, regs.values[r]),
+        (None, "STZ") => (0, Some(Loaded { value: 0, size: 2, insn: c.address })),
+        (None, _) => {
+            regs.clobber(c);
+            return None;
+        }
+    };
+
+    Some(match (value, width(r)) {
+        (Some(v), Some(size)) if size <= v.size => Stored::Known(Loaded { value: v.value & if size == 1 { 0xFF } else { 0xFFFF }, size, ..v }),
+        (_, size) => Stored::Unknown(size.unwrap_or(2))
+    })
+}
@@ -0,0 +1,150 @@
+/* The SPC700 instructions by opcode. Operands are written as {d} direct page, {s} the direct page an
+   instruction reads from when it writes to another, {a} absolute, {i} immediate, {r} relative branch,
+   {m} absolute with a bit number and {u} the low byte of an address in the $FFxx page. */
+pub static SPC_OPCODES: [&str; 256] = [
+    "nop", "tcall 0", "set1 {d}.0", "bbs {d}.0, {r}", "or a, {d}", "or a, {a}", "or a, (x)", "or a, [{d}+x]",
+    "or a, #{i}", "or {d}, {s}", "or1 c, {m}", "asl {d}", "asl {a}", "push p", "tset1 {a}", "brk",
+    "bpl {r}", "tcall 1", "clr1 {d}.0", "bbc {d}.0, {r}", "or a, {d}+x", "or a, {a}+x", "or a, {a}+y", "or a, [{d}]+y",
+    "or {d}, #{i}", "or (x), (y)", "decw {d}", "asl {d}+x", "asl a", "dec x", "cmp x, {a}", "jmp [{a}+x]",
+    "clrp", "tcall 2", "set1 {d}.1", "bbs {d}.1, {r}", "and a, {d}", "and a, {a}", "and a, (x)", "and a, [{d}+x]",
+    "and a, #{i}", "and {d}, {s}", "or1 c, /{m}", "rol {d}", "rol {a}", "push a", "cbne {d}, {r}", "bra {r}",
+    "bmi {r}", "tcall 3", "clr1 {d}.1", "bbc {d}.1, {r}", "and a, {d}+x", "and a, {a}+x", "and a, {a}+y", "and a, [{d}]+y",
+    "and {d}, #{i}", "and (x), (y)", "incw {d}", "rol {d}+x", "rol a", "inc x", "cmp x, {d}", "call {a}",
+    "setp", "tcall 4", "set1 {d}.2", "bbs {d}.2, {r}", "eor a, {d}", "eor a, {a}", "eor a, (x)", "eor a, [{d}+x]",
+    "eor a, #{i}", "eor {d}, {s}", "and1 c, {m}", "lsr {d}", "lsr {a}", "push x", "tclr1 {a}", "pcall {u}",
+    "bvc {r}", "tcall 5", "clr1 {d}.2", "bbc {d}.2, {r}", "eor a, {d}+x", "eor a, {a}+x", "eor a, {a}+y", "eor a, [{d}]+y",
+    "eor {d}, #{i}", "eor (x), (y)", "cmpw ya, {d}", "lsr {d}+x", "lsr a", "mov x, a", "cmp y, {a}", "jmp {a}",
+    "clrc", "tcall 6", "set1 {d}.3", "bbs {d}.3, {r}", "cmp a, {d}", "cmp a, {a}", "cmp a, (x)", "cmp a, [{d}+x]",
+    "cmp a, #{i}", "cmp {d}, {s}", "and1 c, /{m}", "ror {d}", "ror {a}", "push y", "dbnz {d}, {r}", "ret",
+    "bvs {r}", "tcall 7", "clr1 {d}.3", "bbc {d}.3, {r}", "cmp a, {d}+x", "cmp a, {a}+x", "cmp a, {a}+y", "cmp a, [{d}]+y",
+    "cmp {d}, #{i}", "cmp (x), (y)", "addw ya, {d}", "ror {d}+x", "ror a", "mov a, x", "cmp y, {d}", "reti",
+    "setc", "tcall 8", "set1 {d}.4", "bbs {d}.4, {r}", "adc a, {d}", "adc a, {a}", "adc a, (x)", "adc a, [{d}+x]",
+    "adc a, #{i}", "adc {d}, {s}", "eor1 c, {m}", "dec {d}", "dec {a}", "mov y, #{i}", "pop p", "mov {d}, #{i}",
+    "bcc {r}", "tcall 9", "clr1 {d}.4", "bbc {d}.4, {r}", "adc a, {d}+x", "adc a, {a}+x", "adc a, {a}+y", "adc a, [{d}]+y",
+    "adc {d}, #{i}", "adc (x), (y)", "subw ya, {d}", "dec {d}+x", "dec a", "mov x, sp", "div ya, x", "xcn a",
+    "ei", "tcall 10", "set1 {d}.5", "bbs {d}.5, {r}", "sbc a, {d}", "sbc a, {a}", "sbc a, (x)", "sbc a, [{d}+x]",
+    "sbc a, #{i}", "sbc {d}, {s}", "mov1 c, {m}", "inc {d}", "inc {a}", "cmp y, #{i}", "pop a", "mov (x)+, a",
+    "bcs {r}", "tcall 11", "clr1 {d}.5", "bbc {d}.5, {r}", "sbc a, {d}+x", "sbc a, {a}+x", "sbc a, {a}+y", "sbc a, [{d}]+y",
+    "sbc {d}, #{i}", "sbc (x), (y)", "movw ya, {d}", "inc {d}+x", "inc a", "mov sp, x", "das a", "mov a, (x)+",
+    "di", "tcall 12", "set1 {d}.6", "bbs {d}.6, {r}", "mov {d}, a", "mov {a}, a", "mov (x), a", "mov [{d}+x], a",
+    "cmp x, #{i}", "mov {a}, x", "mov1 {m}, c", "mov {d}, y", "mov {a}, y", "mov x, #{i}", "pop x", "mul ya",
+    "bne {r}", "tcall 13", "clr1 {d}.6", "bbc {d}.6, {r}", "mov {d}+x, a", "mov {a}+x, a", "mov {a}+y, a", "mov [{d}]+y, a",
+    "mov {d}, x", "mov {d}+y, x", "movw {d}, ya", "mov {d}+x, y", "dec y", "mov a, y", "cbne {d}+x, {r}", "daa a",
+    "clrv", "tcall 14", "set1 {d}.7", "bbs {d}.7, {r}", "mov a, {d}", "mov a, {a}", "mov a, (x)", "mov a, [{d}+x]",
+    "mov a, #{i}", "mov x, {a}", "not1 {m}", "mov y, {d}", "mov y, {a}", "notc", "pop y", "sleep",
+    "beq {r}", "tcall 15", "clr1 {d}.7", "bbc {d}.7, {r}", "mov a, {d}+x", "mov a, {a}+x", "mov a, {a}+y", "mov a, [{d}]+y",
+    "mov x, {d}", "mov x, {d}+y", "mov {d}, {s}", "mov y, {d}+x", "inc y", "mov y, a", "dbnz y, {r}", "stop"
+];
+
+/* An instruction decoded at an ARAM address, with its operands as (kind, value) in the order they're written */
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub aram: u64,
+    pub bytes: Vec<u8>,
+    pub operands: Vec<(char, u64)>
+}
+
+fn operand_size(kind: char) -> usize {
+    match kind {
+        'a' | 'm' => 2,
+        _ => 1
+    }
+}
+
+fn kinds(template: &str) -> Vec<char> {
+    template.split('{').skip(1).filter_map(|s| s.chars().next()).collect()
+}
+
+impl Instruction {
+    /* Decodes the instruction at the start of "bytes", None if they end before it does */
+    pub fn decode(bytes: &[u8], aram: u64) -> Option<Instruction> {
+        let kinds = kinds(SPC_OPCODES[*bytes.first()? as usize]);
+        let length = 1 + kinds.iter().map(|k| operand_size(*k)).sum::<usize>();
+        let bytes = bytes.get(..length)?.to_vec();
+
+        /* Writes to the direct page with an immediate or another direct page come with the destination last */
+        let mut order: Vec<usize> = (0..kinds.len()).collect();
+        if matches!(kinds[..], ['d', 'i'] | ['d', 's']) {
+            order.reverse();
+        }
+        let mut offset = 1;
+        let mut operands = vec![(' ', 0); kinds.len()];
+        for i in order {
+            let value = (0..operand_size(kinds[i])).map(|b| (bytes[offset + b] as u64) << (b * 8)).sum::<u64>();
+            operands[i] = (kinds[i], value);
+            offset += operand_size(kinds[i]);
+        }
+        Some(Instruction { aram, bytes, operands })
+    }
+
+    /* Where a branch, jump or call goes, pcall and tcall go through the vectors and don't count */
+    pub fn target(&self) -> Option<u64> {
+        self.operands.iter().find_map(|(kind, value)| match kind {
+            'r' => Some((self.aram + self.bytes.len() as u64).wrapping_add(*value as i8 as u64) & 0xFFFF),
+            'a' if matches!(self.bytes[0], 0x3F | 0x5F) => Some(*value),
+            _ => None
+        })
+    }
+
+    /* The instruction as text, with the target written as a label if "label" has one for it */
+    pub fn format(&self, label: &dyn Fn(u64) -> Option<String>) -> String {
+        let template = SPC_OPCODES[self.bytes[0] as usize];
+        let mut output = template.split('{').next().unwrap_or("").to_string();
+        for (part, (kind, value)) in template.split('{').skip(1).zip(&self.operands) {
+            let text = match kind {
+                'a' => self.target().and_then(label).unwrap_or(format!("${:04X}", value)),
+                'r' => self.target().and_then(label).unwrap_or(format!("${:04X}", self.target().unwrap_or(0))),
+                'm' => format!("${:04X}.{}", value & 0x1FFF, value >> 13),
+                _ => format!("${:02X}", value)
+            };
+            output.push_str(&text);
+            output.push_str(&part[2..]);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(bytes: &[u8], aram: u64) -> String {
+        Instruction::decode(bytes, aram).unwrap().format(&|a| Some(format!("L{:04X}", a)).filter(|_| a == 0x0501))
+    }
+
+    #[test]
+    fn destination_last() {
+        /* mov dp, #imm is encoded with the immediate first, mov dp, dp with the source first */
+        assert_eq!(format(&[0x8F, 0x34, 0x12], 0x0500), "mov $12, #$34");
+        assert_eq!(format(&[0xFA, 0x10, 0x20], 0x0500), "mov $20, $10");
+        assert_eq!(format(&[0x09, 0x10, 0x20], 0x0500), "or $20, $10");
+        assert_eq!(format(&[0xE4, 0x10], 0x0500), "mov a, $10");
+    }
+
+    #[test]
+    fn bit_addresses() {
+        assert_eq!(format(&[0xAA, 0x34, 0xA2], 0x0500), "mov1 c, $0234.5");
+        assert_eq!(format(&[0xCA, 0xFF, 0xFF], 0x0500), "mov1 $1FFF.7, c");
+        assert_eq!(Instruction::decode(&[0x0A, 0x34, 0xA2], 0x0500).unwrap().target(), None);
+    }
+
+    #[test]
+    fn three_byte_branches() {
+        /* The branch is relative to the end of the instruction, after the direct page byte */
+        assert_eq!(format(&[0x2E, 0x10, 0xFE], 0x0500), "cbne $10, L0501");
+        assert_eq!(format(&[0x6E, 0x10, 0x03], 0x0500), "dbnz $10, $0506");
+        assert_eq!(format(&[0x03, 0x10, 0xFD], 0x0500), "bbs $10.0, $0500");
+        assert_eq!(format(&[0xF3, 0x10, 0x00], 0x0500), "bbc $10.7, $0503");
+        assert_eq!(Instruction::decode(&[0xDE, 0x10, 0x80], 0x0000).unwrap().target(), Some(0xFF83));
+        assert_eq!(format(&[0xFE, 0xFE], 0x0500), "dbnz y, $0500");
+    }
+
+    #[test]
+    fn jumps_and_truncated() {
+        assert_eq!(format(&[0x3F, 0x01, 0x05], 0x0400), "call L0501");
+        assert_eq!(Instruction::decode(&[0x5F, 0x00, 0x06], 0x0400).unwrap().target(), Some(0x0600));
+        assert_eq!(Instruction::decode(&[0xE5, 0x00, 0x06], 0x0400).unwrap().target(), None);
+        assert!(Instruction::decode(&[0x8F, 0x34], 0x0500).is_none());
+        assert!(Instruction::decode(&[], 0x0500).is_none());
+    }
+}
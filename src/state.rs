@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use if_chain::if_chain;
+
+use crate::{code::{ArgType, Code}, config::Config, line::Line, opcode::AddrMode};
+
+/* Tracks the CPU state that the disassembly needs but the logs don't always spell out.
+   The listing is walked linearly in address order, branch targets inherit the state at the
+   branch site and every ";;;" routine header starts over from the routine's entry state. */
+#[derive(Debug, Clone, PartialEq)]
+struct RegState {
+    a_lo: Option<u8>,
+    a_hi: Option<u8>,
+    d: Option<u16>,
+    db: Option<u8>,
+    m: Option<bool>,
+    x: Option<bool>,
+    stack: Vec<Option<u8>>
+}
+
+impl RegState {
+    fn entry() -> RegState {
+        RegState { a_lo: None, a_hi: None, d: Some(0), db: None, m: None, x: None, stack: Vec::new() }
+    }
+
+    fn a(&self) -> Option<u16> {
+        match (self.a_lo, self.a_hi) {
+            (Some(lo), Some(hi)) => Some(((hi as u16) << 8) | lo as u16),
+            _ => None
+        }
+    }
+
+    fn set_a(&mut self, value: Option<u16>) {
+        self.a_lo = value.map(|v| (v & 0xFF) as u8);
+        self.a_hi = value.map(|v| (v >> 8) as u8);
+    }
+
+    fn push(&mut self, value: Option<u16>, size: u8) {
+        if size == 2 {
+            self.stack.push(value.map(|v| (v >> 8) as u8));
+        }
+        self.stack.push(value.map(|v| (v & 0xFF) as u8));
+    }
+
+    fn pull(&mut self, size: u8) -> Option<u16> {
+        let lo = self.stack.pop().flatten();
+        if size == 2 {
+            let hi = self.stack.pop().flatten();
+            match (lo, hi) {
+                (Some(lo), Some(hi)) => Some(((hi as u16) << 8) | lo as u16),
+                _ => None
+            }
+        } else {
+            lo.map(|l| l as u16)
+        }
+    }
+
+    /* Keep only what both incoming paths agree on */
+    fn merge(&self, other: &RegState) -> RegState {
+        RegState {
+            a_lo: if self.a_lo == other.a_lo { self.a_lo } else { None },
+            a_hi: if self.a_hi == other.a_hi { self.a_hi } else { None },
+            d: if self.d == other.d { self.d } else { None },
+            db: if self.db == other.db { self.db } else { None },
+            m: if self.m == other.m { self.m } else { None },
+            x: if self.x == other.x { self.x } else { None },
+            stack: if self.stack == other.stack { self.stack.clone() } else { Vec::new() }
+        }
+    }
+
+    fn acc_size(&self) -> u8 {
+        if self.m == Some(true) { 1 } else { 2 }
+    }
+
+    fn index_size(&self) -> u8 {
+        if self.x == Some(true) { 1 } else { 2 }
+    }
+}
+
+#[derive(Debug)]
+pub struct DataBankMismatch {
+    pub address: u64,
+    pub log_db: u8,
+    pub inferred_db: u8
+}
+
+/* Register values seen at an instruction: D, DB, M and X */
+type InsnState = (Option<u16>, Option<u8>, Option<bool>, Option<bool>);
+
+/* Register values seen at each instruction, plus the data bank each call site passes on */
+struct Walk {
+    states: HashMap<u64, InsnState>,
+    calls: HashMap<u64, Option<u8>>
+}
+
+fn walk(lines: &BTreeMap<u64, Vec<Line>>, config: &Config, entry_db: &HashMap<u64, u8>) -> Walk {
+    let mut result = Walk { states: HashMap::new(), calls: HashMap::new() };
+    let mut state = RegState::entry();
+    let mut pending: HashMap<u64, RegState> = HashMap::new();
+    let mut reachable = true;
+    let mut fresh = true;
+    /* Calls to subroutines with inline arguments come back after the argument data */
+    let inline: HashSet<u64> = config.labels.iter().filter(|l| l.inline.is_some()).map(|l| l.addr).collect();
+    let mut args = false;
+
+    for line in lines.values().flatten() {
+        let c = match line {
+            Line::Code(c) => c,
+            Line::Comment(s) if s.starts_with(";;;") => {
+                state = RegState::entry();
+                reachable = true;
+                fresh = true;
+                continue;
+            },
+            Line::Comment(_) => continue,
+            Line::Data(_) if args => {
+                args = false;
+                continue;
+            },
+            Line::Data(_) | Line::Binary(_) | Line::Apu(_) => {
+                reachable = false;
+                continue;
+            }
+        };
+        args = false;
+
+        state = match (reachable, pending.remove(&c.address)) {
+            (true, Some(p)) => state.merge(&p),
+            (false, Some(p)) => p,
+            (true, None) => state,
+            (false, None) => {
+                fresh = true;
+                RegState::entry()
+            }
+        };
+        reachable = true;
+
+        if fresh {
+            state.db = entry_db.get(&c.address).copied();
+            fresh = false;
+        }
+
+        if let Some(dp) = config.get_direct_page(c.address) {
+            state.d = Some(dp as u16);
+        }
+
+        if_chain! {
+            if let Some(ov) = config.get_override(c.address);
+            if ov._type.is_none() || ov._type.as_deref() == Some("Code");
+            if let Some(db) = ov.db;
+            then {
+                state.db = Some(db as u8);
+            }
+        }
+
+        result.states.insert(c.address, (state.d, state.db, state.m, state.x));
+        step(&mut state, c);
+
+        let arg = match c.arg {
+            ArgType::Address(a) => Some(a),
+            _ => None
+        };
+
+        match (c.opcode.name, &c.opcode.addr_mode, arg) {
+            ("JSR", AddrMode::Absolute, Some(a)) | ("JSL", _, Some(a)) => {
+                let target = if c.opcode.name == "JSR" { (c.address & 0xFF0000) | (a & 0xFFFF) } else { a };
+                let db = match result.calls.get(&target) {
+                    Some(db) if *db != state.db => None,
+                    _ => state.db
+                };
+                result.calls.insert(target, db);
+                args = inline.contains(&target);
+            },
+            _ => ()
+        }
+
+        /* Hand the state over to the branch target */
+        let target = c.branch_target().filter(|_| !matches!(c.opcode.name, "JSR" | "JSL"));
+
+        if let Some(target) = target {
+            if target > c.address {
+                let merged = match pending.get(&target) {
+                    Some(p) => p.merge(&state),
+                    None => state.clone()
+                };
+                pending.insert(target, merged);
+            }
+        }
+
+        if matches!(c.opcode.name, "RTS" | "RTL" | "RTI" | "JMP" | "JML" | "BRA" | "BRL" | "STP") {
+            reachable = false;
+        }
+    }
+
+    result
+}
+
+/* Applies the effect of a single instruction to the tracked registers */
+fn step(state: &mut RegState, c: &Code) {
+    let arg = match c.arg {
+        ArgType::Address(a) => Some(a),
+        _ => None
+    };
+
+    match (c.opcode.name, &c.opcode.addr_mode) {
+        ("LDA", AddrMode::Immediate) => {
+            let value = arg.unwrap() as u16;
+            if c.length == 2 {
+                state.set_a(Some(value));
+                state.m = Some(false);
+            } else {
+                state.a_lo = Some(value as u8);
+                state.m = Some(true);
+            }
+        },
+        ("ADC", AddrMode::Immediate) | ("AND", AddrMode::Immediate) | ("EOR", AddrMode::Immediate) |
+        ("ORA", AddrMode::Immediate) | ("SBC", AddrMode::Immediate) => {
+            state.set_a(None);
+            state.m = Some(c.length == 1);
+        },
+        ("CMP", AddrMode::Immediate) | ("BIT", AddrMode::Immediate) => state.m = Some(c.length == 1),
+        ("CPX", AddrMode::Immediate) | ("CPY", AddrMode::Immediate) |
+        ("LDX", AddrMode::Immediate) | ("LDY", AddrMode::Immediate) => state.x = Some(c.length == 1),
+        ("REP", _) | ("SEP", _) => {
+            let flags = arg.unwrap();
+            let set = c.opcode.name == "SEP";
+            if flags & 0x20 != 0 {
+                state.m = Some(set);
+            }
+            if flags & 0x10 != 0 {
+                state.x = Some(set);
+            }
+        },
+        ("LDA", _) | ("ADC", _) | ("AND", _) | ("EOR", _) | ("ORA", _) | ("SBC", _) |
+        ("TXA", _) | ("TYA", _) | ("TSC", _) => state.set_a(None),
+        ("ASL", AddrMode::Implied) | ("LSR", AddrMode::Implied) | ("ROL", AddrMode::Implied) |
+        ("ROR", AddrMode::Implied) | ("INC", AddrMode::Implied) | ("DEC", AddrMode::Implied) => state.set_a(None),
+        ("XBA", _) => {
+            std::mem::swap(&mut state.a_lo, &mut state.a_hi);
+        },
+        ("TDC", _) => state.set_a(state.d),
+        ("TCD", _) => state.d = state.a(),
+        ("PHA", _) => {
+            let size = state.acc_size();
+            let value = if size == 1 { state.a_lo.map(|v| v as u16) } else { state.a() };
+            state.push(value, size);
+        },
+        ("PLA", _) => {
+            let size = state.acc_size();
+            let value = state.pull(size);
+            if size == 1 {
+                state.a_lo = value.map(|v| v as u8);
+            } else {
+                state.set_a(value);
+            }
+        },
+        ("PHX", _) | ("PHY", _) => {
+            let size = state.index_size();
+            state.push(None, size);
+        },
+        ("PLX", _) | ("PLY", _) => {
+            let size = state.index_size();
+            state.pull(size);
+        },
+        ("PHD", _) => state.push(state.d, 2),
+        ("PLD", _) => state.d = state.pull(2),
+        ("PEA", _) => state.push(arg.map(|a| a as u16), 2),
+        ("PEI", _) | ("PER", _) => state.push(None, 2),
+        ("PHP", _) => state.push(None, 1),
+        ("PHB", _) => state.push(state.db.map(|b| b as u16), 1),
+        ("PHK", _) => state.push(Some((c.address >> 16) as u16), 1),
+        ("PLB", _) => state.db = state.pull(1).map(|b| b as u8),
+        ("PLP", _) => {
+            state.pull(1);
+            state.m = None;
+            state.x = None;
+        },
+        ("MVN", _) | ("MVP", _) => {
+            if let ArgType::BlockMove(_, dst) = c.arg {
+                state.db = Some(dst);
+            }
+            state.set_a(None);
+        },
+        ("TCS", _) | ("TXS", _) => state.stack.clear(),
+        ("JSR", _) | ("JSL", _) => state.set_a(None),
+        _ => ()
+    }
+}
+
+/* Follows the direct page and data bank registers through the code and stores them on each
+   instruction. A routine's entry data bank comes from its callers when they all agree, which
+   takes a few rounds to settle since callers are routines themselves. */
+pub fn track_registers(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<DataBankMismatch> {
+    let mut entry_db: HashMap<u64, u8> = HashMap::new();
+    let mut result = walk(lines, config, &entry_db);
+
+    for _ in 0..8 {
+        let next_entry_db: HashMap<u64, u8> = result.calls.iter()
+            .filter_map(|(addr, db)| db.map(|db| (*addr, db)))
+            .collect();
+
+        if next_entry_db == entry_db {
+            break;
+        }
+
+        entry_db = next_entry_db;
+        result = walk(lines, config, &entry_db);
+    }
+
+    let mut mismatches = Vec::new();
+    for line in lines.values_mut().flatten() {
+        if let Line::Code(c) = line {
+            if let Some((dp, db, m, x)) = result.states.get(&c.address) {
+                c.dp = *dp;
+                c.acc_8bit = *m;
+                c.index_8bit = *x;
+                if let Some(db) = db {
+                    if_chain! {
+                        if c.uses_data_bank();
+                        if let Some(log_db) = c.log_db;
+                        if log_db != *db;
+                        then {
+                            mismatches.push(DataBankMismatch { address: c.address, log_db, inferred_db: *db });
+                        }
+                    }
+                    c.db = *db;
+                }
+            }
+        }
+    }
+
+    mismatches
+}
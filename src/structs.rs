@@ -0,0 +1,562 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt};
+use if_chain::if_chain;
+
+use crate::{config::{Config, Override, OverrideAddr, Struct}, constants, data::Data, line::Line};
+
+/* Structs nest this deep at most, anything deeper is taken to be a struct containing itself */
+const MAX_DEPTH: usize = 8;
+
+/* Lists that end in a terminator stop after this many records if the terminator never shows up */
+const MAX_RECORDS: u64 = 0x1000;
+
+#[derive(Debug)]
+pub enum StructError {
+    UnknownStruct { name: String },
+    BadField { st: String, field: String, reason: String },
+    Overlap { st: String, field: String },
+    Recursive { st: String },
+    Misaligned { address: u64, st: String, field: String },
+    MissingData { address: u64, st: String },
+    NoEnd { address: u64, st: String }
+}
+
+impl fmt::Display for StructError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StructError::UnknownStruct { name } => write!(f, "unknown struct \"{}\"", name),
+            StructError::BadField { st, field, reason } => write!(f, "{}.{}: {}", st, field, reason),
+            StructError::Overlap { st, field } => write!(f, "{}.{}: overlaps the field before it", st, field),
+            StructError::Recursive { st } => write!(f, "{}: contains itself", st),
+            StructError::Misaligned { address, st, field } =>
+                write!(f, "${:06X}: {}.{} doesn't line up with the data, written as plain values", address, st, field),
+            StructError::MissingData { address, st } => write!(f, "${:06X}: {} runs past the end of the data", address, st),
+            StructError::NoEnd { address, st } => write!(f, "${:06X}: {} list doesn't end within {} records", address, st, MAX_RECORDS)
+        }
+    }
+}
+
+/* A single value of a struct after nested structs and arrays have been flattened out */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leaf {
+    pub offset: u64,
+    pub length: u64,
+    pub path: String,
+    pub _type: String,
+    pub db: Option<u64>,
+    pub base: Option<u64>,
+    pub target: Option<String>,
+    pub _enum: Option<String>
+}
+
+#[derive(Debug)]
+pub struct Layout {
+    pub size: u64,
+    pub leaves: Vec<Leaf>
+}
+
+/* What a struct-tagged value is: which record it belongs to and which field of it. Records that
+   aren't labelled only get a label when something points at them. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldTag {
+    pub st: String,
+    pub record: u64,
+    pub leaf: Leaf,
+    pub labelled: bool
+}
+
+impl FieldTag {
+    pub fn is_terminator(&self) -> bool {
+        self.leaf.path.is_empty()
+    }
+}
+
+fn find<'a>(config: &'a Config, name: &str) -> Result<&'a Struct, StructError> {
+    config.structs.iter().find(|s| s.name == name).ok_or(StructError::UnknownStruct { name: name.to_string() })
+}
+
+/* Flattens a struct definition into its values, checking that the fields make sense on the way */
+pub fn layout(config: &Config, name: &str) -> Result<Layout, StructError> {
+    layout_at(config, name, 0)
+}
+
+fn layout_at(config: &Config, name: &str, depth: usize) -> Result<Layout, StructError> {
+    if depth > MAX_DEPTH {
+        return Err(StructError::Recursive { st: name.to_string() });
+    }
+
+    let st = find(config, name)?;
+    let bad_field = |field: &str, reason: &str| StructError::BadField { st: name.to_string(), field: field.to_string(), reason: reason.to_string() };
+
+    let mut fields: Vec<_> = st.fields.iter().collect();
+    fields.sort_by_key(|f| f.offset);
+
+    let mut leaves = Vec::new();
+    let mut end = 0;
+    for field in fields {
+        if field.offset < end {
+            return Err(StructError::Overlap { st: name.to_string(), field: field.name.to_string() });
+        }
+
+        let count = field.count.unwrap_or(1);
+        if count == 0 {
+            return Err(bad_field(&field.name, "count can't be 0"));
+        }
+
+        let (length, inner) = if field._type == "Struct" {
+            let inner_name = field._struct.as_ref().ok_or_else(|| bad_field(&field.name, "nested struct without a \"struct\" name"))?;
+            let inner = layout_at(config, inner_name, depth + 1)?;
+            if field.length != 0 && field.length != inner.size {
+                return Err(bad_field(&field.name, &format!("length {} but {} is {} bytes", field.length, inner_name, inner.size)));
+            }
+            (inner.size, Some(inner))
+        } else {
+            if !(1..=3).contains(&field.length) {
+                return Err(bad_field(&field.name, "length has to be 1, 2 or 3"));
+            }
+            if field._type == "Offset" && field.base.is_none() {
+                return Err(bad_field(&field.name, "offset without a \"base\""));
+            }
+            if let Some(target) = &field.target {
+                find(config, target)?;
+            }
+            if field._type == "Enum" || field._type == "Flags" {
+                let set = field._enum.as_ref().ok_or_else(|| bad_field(&field.name, "enum field without an \"enum\" name"))?;
+                constants::find(config, set).ok_or_else(|| bad_field(&field.name, &format!("unknown constants \"{}\"", set)))?;
+            }
+            (field.length, None)
+        };
+
+        for i in 0..count {
+            let path = if field.count.is_some() { format!("{}[{}]", field.name, i) } else { field.name.to_string() };
+            let offset = field.offset + i * length;
+            match &inner {
+                Some(inner) => leaves.extend(inner.leaves.iter().map(|l| Leaf {
+                    offset: offset + l.offset,
+                    path: format!("{}.{}", path, l.path),
+                    ..l.clone()
+                })),
+                None => leaves.push(Leaf {
+                    offset,
+                    length,
+                    path,
+                    _type: field._type.to_string(),
+                    db: field.db,
+                    base: field.base,
+                    target: field.target.clone(),
+                    _enum: field._enum.clone()
+                })
+            }
+        }
+
+        end = field.offset + length * count;
+    }
+
+    if leaves.is_empty() {
+        return Err(bad_field("", "struct without fields"));
+    }
+
+    Ok(Layout { size: end, leaves })
+}
+
+/* Tags every value covered by a struct, starting from the struct overrides and following pointer
+   fields that name the struct they point at. Lists with a terminator run until the terminator, those
+   with "last" up to and including the record that starts with it, those with "until_below" until a
+   record starts with a smaller value, anything reached through a pointer without any of them is a
+   single record. A list stops at the first record that doesn't line up. Structs with a "list" give
+   back an Instructions override for the instruction list after each of their records. */
+pub fn resolve(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> (HashMap<u64, FieldTag>, Vec<StructError>, Vec<Override>) {
+    let mut tags: HashMap<u64, FieldTag> = HashMap::new();
+    let mut errors: Vec<StructError> = Vec::new();
+    let mut lists: Vec<Override> = Vec::new();
+
+    /* Every data value by address, along with its size */
+    let mut values: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    for line in lines.values().flatten() {
+        if let Line::Data(d) = line {
+            values.extend(d.entries().into_iter().map(|(a, v)| (a, (v.size(), v.as_u64()))));
+        }
+    }
+    let read = |addr: u64, length: u64| -> Option<u64> {
+        (0..length).map(|i| {
+            values.range(..=addr + i).next_back()
+                .filter(|(a, (size, _))| addr + i < *a + size)
+                .map(|(a, (_, v))| ((v >> ((addr + i - a) * 8)) & 0xFF) << (i * 8))
+        }).sum()
+    };
+
+    let mut layouts: HashMap<String, Option<Layout>> = HashMap::new();
+    let mut pending: Vec<(u64, String, Option<u64>)> = config.overrides.iter()
+        .filter(|ov| ov._type.as_deref() == Some("Struct"))
+        .filter_map(|ov| {
+            let (start, end) = match &ov.addr {
+                OverrideAddr::Range(r) => (r[0], Some(r[1])),
+                OverrideAddr::Address(a) => (*a, None)
+            };
+            ov._struct.as_ref().map(|s| (start, s.to_string(), end))
+        })
+        .rev()
+        .collect();
+    let mut visited: HashSet<(u64, String)> = HashSet::new();
+
+    while let Some((start, name, end)) = pending.pop() {
+        if !visited.insert((start, name.to_string())) {
+            continue;
+        }
+
+        if !layouts.contains_key(&name) {
+            let result = layout(config, &name).map_err(|e| errors.push(e)).ok();
+            layouts.insert(name.to_string(), result);
+        }
+        let layout = match &layouts[&name] {
+            Some(l) => l,
+            None => continue
+        };
+        let st = config.structs.iter().find(|s| s.name == name);
+        let terminator = st.and_then(|s| s.terminator);
+        let below = st.and_then(|s| s.until_below);
+        let last = st.and_then(|s| s.last);
+        let is_list = end.is_some() || terminator.is_some() || last.is_some() || below.is_some();
+        let first = &layout.leaves[0];
+
+        let mut record = start;
+        let mut ended = false;
+        for _ in 0..MAX_RECORDS {
+            if end.map(|e| record > e).unwrap_or(false) {
+                break;
+            }
+
+            let first_value = read(record + first.offset, first.length);
+            if first_value.is_none() {
+                /* Nothing at the start at all just means that bank wasn't loaded */
+                if record != start && is_list {
+                    errors.push(StructError::MissingData { address: record, st: name.to_string() });
+                }
+                ended = true;
+                break;
+            }
+
+            if terminator.is_some() && first_value == terminator {
+                let leaf = Leaf { offset: 0, length: first.length, path: "".to_string(), _type: "Data".to_string(), db: None, base: None, target: None, _enum: None };
+                tags.entry(record).or_insert(FieldTag { st: name.to_string(), record, leaf, labelled: true });
+                ended = true;
+                break;
+            }
+            if below.is_some() && first_value < below {
+                ended = true;
+                break;
+            }
+
+            let mut aligned = true;
+            for leaf in &layout.leaves {
+                let addr = record + leaf.offset;
+                if values.get(&addr).map(|(size, _)| *size) != Some(leaf.length) {
+                    if aligned {
+                        errors.push(StructError::Misaligned { address: addr, st: name.to_string(), field: leaf.path.to_string() });
+                    }
+                    aligned = false;
+                    continue;
+                }
+
+                tags.entry(addr).or_insert(FieldTag { st: name.to_string(), record, leaf: leaf.clone(), labelled: true });
+
+                if_chain! {
+                    if leaf._type == "Pointer";
+                    if let Some(target) = &leaf.target;
+                    if let Some(value) = read(addr, leaf.length);
+                    if (value & 0xFFFF) >= 0x8000;
+                    then {
+                        let target_addr = if leaf.length < 3 { (leaf.db.unwrap_or(addr >> 16) << 16) | (value & 0xFFFF) } else { value };
+                        pending.push((target_addr, target.to_string(), None));
+                    }
+                }
+            }
+
+            /* Whatever comes after a record that doesn't line up can't be trusted to be the next one */
+            if !aligned {
+                ended = true;
+                break;
+            }
+
+            if let Some(set) = st.and_then(|s| s.list.as_ref()) {
+                lists.push(Override {
+                    addr: OverrideAddr::Address(record + layout.size),
+                    _type: Some("Instructions".to_string()),
+                    set: Some(set.to_string()),
+                    ..Default::default()
+                });
+            }
+
+            record += layout.size;
+            if !is_list || (last.is_some() && first_value == last) {
+                ended = true;
+                break;
+            }
+        }
+
+        if !ended && end.is_none() {
+            errors.push(StructError::NoEnd { address: start, st: name.to_string() });
+        }
+    }
+
+    (tags, errors, lists)
+}
+
+/* The address a struct pointer field points at, if the value is one */
+pub fn pointer_target(tag: &FieldTag, cur_pc: u64, value: u64) -> Option<u64> {
+    if tag.leaf._type != "Pointer" {
+        return None;
+    }
+
+    let db = tag.leaf.db.unwrap_or(cur_pc >> 16);
+    let label_addr = if tag.leaf.length < 3 { (value & 0xFFFF_u64) | (db << 16) } else { value };
+    Some(label_addr).filter(|a| (a & 0xFFFF_u64) >= 0x8000)
+}
+
+/* Rearranges struct-tagged data so that every record sits on a line of its own. Records that the
+   log spread over several lines are joined up, lines that hold several records get split. */
+pub fn split_records(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config) {
+    let record_of = |addr: u64| config.struct_tags.get(&addr).map(|t| t.record);
+    let mut split: BTreeMap<u64, Vec<Line>> = BTreeMap::new();
+    let mut last_data: Option<u64> = None;
+
+    for (addr, addr_lines) in std::mem::take(lines) {
+        for line in addr_lines {
+            let d = match line {
+                Line::Data(d) if d.entries().iter().any(|(a, _)| record_of(*a).is_some()) => d,
+                _ => {
+                    last_data = None;
+                    split.entry(addr).or_default().push(line);
+                    continue;
+                }
+            };
+
+            /* Cut before every record start and wherever the data goes in or out of a struct */
+            let mut chunks: Vec<Data> = Vec::new();
+            let mut prev_record: Option<Option<u64>> = None;
+            for (a, v) in d.entries() {
+                let record = record_of(a);
+                if record == Some(a) || prev_record != Some(record) {
+                    chunks.push(Data { address: a, data: Vec::new(), comment: if chunks.is_empty() { d.comment.clone() } else { None } });
+                }
+                chunks.last_mut().unwrap().data.push(v.clone());
+                prev_record = Some(record);
+            }
+
+            for chunk in chunks {
+                let record = record_of(chunk.address);
+                let continues = if_chain! {
+                    if let Some(record) = record;
+                    if record != chunk.address;
+                    if let Some(last) = last_data;
+                    if let Some(Line::Data(prev)) = split.get(&last).and_then(|l| l.last());
+                    if record_of(prev.address) == Some(record);
+                    if prev.address + prev.data.iter().map(|v| v.size()).sum::<u64>() == chunk.address;
+                    then { true } else { false }
+                };
+
+                if continues {
+                    if let Some(Line::Data(prev)) = split.get_mut(&last_data.unwrap()).and_then(|l| l.last_mut()) {
+                        prev.data.extend(chunk.data);
+                        if let Some(comment) = chunk.comment {
+                            prev.comment = Some(match &prev.comment {
+                                Some(c) => format!("{} {}", c, comment),
+                                None => comment
+                            });
+                        }
+                    }
+                } else {
+                    last_data = Some(chunk.address);
+                    split.entry(chunk.address).or_default().push(Line::Data(chunk));
+                }
+            }
+        }
+    }
+
+    *lines = split;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Options, data::DataVal};
+
+    fn config(structs: &str, overrides: &str) -> Config {
+        Config {
+            labels: Vec::new(),
+            overrides: serde_yaml::from_str(overrides).unwrap(),
+            structs: serde_yaml::from_str(structs).unwrap(),
+            constants: Vec::new(),
+            instruction_sets: Vec::new(),
+            packs: Vec::new(),
+            options: Options::default(),
+            byte_refs: HashMap::new(),
+            struct_tags: HashMap::new(),
+            immediate_enums: HashMap::new()
+        }
+    }
+
+    /* One data line per slice of words */
+    fn words(lines: &[(u64, &[u16])]) -> BTreeMap<u64, Vec<Line>> {
+        lines.iter().map(|(address, words)| {
+            (*address, vec![Line::Data(Data { address: *address, data: words.iter().map(|w| DataVal::DW(*w)).collect(), comment: None })])
+        }).collect()
+    }
+
+    const POINT: &str = "
+- name: Point
+  fields:
+    - { name: X, offset: 0, length: 2, type: Data }
+    - { name: Y, offset: 2, length: 2, type: Data }
+";
+
+    #[test]
+    fn layout_flattens() {
+        let config = config(&format!("{}
+- name: Shape
+  fields:
+    - {{ name: Corners, offset: 2, type: Struct, struct: Point, count: 2 }}
+    - {{ name: Id, offset: 0, length: 2, type: Data }}
+", POINT), "[]");
+        let layout = layout(&config, "Shape").unwrap();
+        assert_eq!(layout.size, 10);
+        let leaves: Vec<_> = layout.leaves.iter().map(|l| (l.offset, l.path.as_str())).collect();
+        assert_eq!(leaves, [(0, "Id"), (2, "Corners[0].X"), (4, "Corners[0].Y"), (6, "Corners[1].X"), (8, "Corners[1].Y")]);
+    }
+
+    #[test]
+    fn layout_errors() {
+        let config = config("
+- name: Overlapping
+  fields:
+    - { name: A, offset: 0, length: 2, type: Data }
+    - { name: B, offset: 1, length: 1, type: Data }
+- name: TooLong
+  fields:
+    - { name: A, offset: 0, length: 4, type: Data }
+- name: NoBase
+  fields:
+    - { name: A, offset: 0, length: 2, type: Offset }
+- name: Itself
+  fields:
+    - { name: A, offset: 0, type: Struct, struct: Itself }
+- name: Empty
+  fields: []
+", "[]");
+        assert!(matches!(layout(&config, "Overlapping"), Err(StructError::Overlap { .. })));
+        assert!(matches!(layout(&config, "TooLong"), Err(StructError::BadField { .. })));
+        assert!(matches!(layout(&config, "NoBase"), Err(StructError::BadField { .. })));
+        assert!(matches!(layout(&config, "Itself"), Err(StructError::Recursive { .. })));
+        assert!(matches!(layout(&config, "Empty"), Err(StructError::BadField { .. })));
+        assert!(matches!(layout(&config, "Missing"), Err(StructError::UnknownStruct { .. })));
+    }
+
+    #[test]
+    fn resolve_terminated_list() {
+        let config = config(&format!("{}
+- name: Points
+  terminator: 0xFFFF
+  fields:
+    - {{ name: Pos, offset: 0, type: Struct, struct: Point }}
+", POINT), "[{ addr: 0x8F8000, type: Struct, struct: Points }]");
+        let lines = words(&[(0x8F8000, &[1, 2, 3, 4, 0xFFFF, 5])]);
+        let (tags, errors, _) = resolve(&lines, &config);
+        assert!(errors.is_empty());
+        assert_eq!(tags[&0x8F8006].leaf.path, "Pos.Y");
+        assert_eq!(tags[&0x8F8006].record, 0x8F8004);
+        assert!(tags[&0x8F8008].is_terminator());
+        assert!(!tags.contains_key(&0x8F800A));
+    }
+
+    #[test]
+    fn resolve_follows_pointers() {
+        let config = config(&format!("{}
+- name: Rooms
+  until_below: 0x8000
+  list: RoomCode
+  fields:
+    - {{ name: Pos, offset: 0, length: 2, type: Pointer, db: 0x8F, target: Point }}
+- name: Marker
+  list: MarkerCode
+  fields:
+    - {{ name: Id, offset: 0, length: 2, type: Data }}
+", POINT), "[{ addr: 0x8F8000, type: Struct, struct: Rooms }, { addr: 0x8F9000, type: Struct, struct: Marker }]");
+        let lines = words(&[(0x8F8000, &[0x9000, 0x9000, 0x0001]), (0x8F9000, &[7, 8])]);
+        let (tags, errors, lists) = resolve(&lines, &config);
+        assert!(errors.is_empty());
+        assert_eq!(tags[&0x8F8002].st, "Rooms");
+        assert_eq!(tags[&0x8F9002].st, "Point");
+        assert!(!tags.contains_key(&0x8F8004));
+
+        /* The Point at $8F9000 doesn't stop the Marker at the same address from being walked */
+        let lists: Vec<_> = lists.iter().map(|l| (&l.addr, l.set.as_deref().unwrap())).collect();
+        assert_eq!(lists, [
+            (&OverrideAddr::Address(0x8F8002), "RoomCode"),
+            (&OverrideAddr::Address(0x8F8004), "RoomCode"),
+            (&OverrideAddr::Address(0x8F9002), "MarkerCode")
+        ]);
+    }
+
+    #[test]
+    fn resolve_list_with_last_record() {
+        let config = config(&format!("{}
+- name: Points
+  terminator: 0xFFFF
+  last: 0x0000
+  fields:
+    - {{ name: Pos, offset: 0, type: Struct, struct: Point }}
+", POINT), "[{ addr: 0x8F8000, type: Struct, struct: Points }, { addr: 0x8F8010, type: Struct, struct: Points }]");
+        let lines = words(&[(0x8F8000, &[1, 2, 0, 3, 4, 5]), (0x8F8010, &[0xFFFF, 6])]);
+        let (tags, errors, _) = resolve(&lines, &config);
+        assert!(errors.is_empty());
+        assert_eq!(tags[&0x8F8006].leaf.path, "Pos.Y");
+        assert!(!tags.contains_key(&0x8F8008));
+        assert!(tags[&0x8F8010].is_terminator());
+    }
+
+    #[test]
+    fn resolve_stops_at_misaligned_record() {
+        let config = config(&format!("{}
+- name: Points
+  terminator: 0xFFFF
+  fields:
+    - {{ name: Pos, offset: 0, type: Struct, struct: Point }}
+", POINT), "[{ addr: 0x8F8000, type: Struct, struct: Points }]");
+        let mut lines = words(&[(0x8F8000, &[1, 2])]);
+        lines.insert(0x8F8004, vec![Line::Data(Data { address: 0x8F8004, data: vec![DataVal::DB(3), DataVal::DB(0), DataVal::DW(4), DataVal::DW(5), DataVal::DW(6), DataVal::DW(0xFFFF)], comment: None })]);
+        let (tags, errors, _) = resolve(&lines, &config);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], StructError::Misaligned { address: 0x8F8004, .. }));
+        assert!(!tags.contains_key(&0x8F8008));
+        assert!(!tags.contains_key(&0x8F800C));
+    }
+
+    #[test]
+    fn resolve_list_without_end() {
+        let config = config("
+- name: Values
+  terminator: 0xFFFF
+  fields:
+    - { name: Value, offset: 0, length: 2, type: Data }
+", "[{ addr: 0x8F8000, type: Struct, struct: Values }]");
+        let values = vec![1; MAX_RECORDS as usize + 1];
+        let lines = words(&[(0x8F8000, &values)]);
+        let (_, errors, _) = resolve(&lines, &config);
+        assert!(matches!(errors[..], [StructError::NoEnd { address: 0x8F8000, .. }]));
+    }
+
+    #[test]
+    fn split_records_one_per_line() {
+        let mut config = config(POINT, "[{ addr: [0x8F8000, 0x8F800B], type: Struct, struct: Point }]");
+        /* Two records and a half on the first line, the rest of the third on the next, then untagged data */
+        let mut lines = words(&[(0x8F8000, &[1, 2, 3, 4, 5]), (0x8F800A, &[6, 7])]);
+        let (tags, _, _) = resolve(&lines, &config);
+        config.struct_tags = tags;
+        split_records(&mut lines, &config);
+
+        let split: Vec<_> = lines.values().flatten().map(|l| match l {
+            Line::Data(d) => (d.address, d.data.iter().map(|v| v.as_u64()).collect::<Vec<_>>()),
+            _ => panic!()
+        }).collect();
+        assert_eq!(split, [(0x8F8000, vec![1, 2]), (0x8F8004, vec![3, 4]), (0x8F8008, vec![5, 6]), (0x8F800C, vec![7])]);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::{config::Config, data::DataVal, label::{LabelType, LABELS}, line::Line, tables::Confidence};
+
+const MIN_RUN: usize = 3;
+
+#[derive(Debug)]
+pub struct Suggestion {
+    pub start: u64,
+    pub end: u64,
+    pub db: u64,
+    pub hits: usize,
+    pub confidence: Confidence,
+    pub reason: String
+}
+
+/* Longest stretch of words that all point at labels in the given bank. Null words are
+   allowed inside a stretch since tables often have empty slots, but not at either end. */
+fn longest_stretch(words: &[(u64, u16)], bank: u64, labels: &BTreeSet<u64>) -> Option<(usize, usize, usize)> {
+    let hit = |w: u16| w >= 0x8000 && labels.contains(&((bank << 16) | w as u64));
+    let mut best: Option<(usize, usize, usize)> = None;
+    let mut start: Option<usize> = None;
+    let mut last_hit = 0;
+    let mut hits = 0;
+
+    for (i, (_, w)) in words.iter().enumerate() {
+        if hit(*w) {
+            if start.is_none() {
+                start = Some(i);
+                hits = 0;
+            }
+            last_hit = i;
+            hits += 1;
+        } else if *w != 0 {
+            if let Some(s) = start.take() {
+                if hits >= MIN_RUN && best.map(|b| hits > b.2).unwrap_or(true) {
+                    best = Some((s, last_hit, hits));
+                }
+            }
+        }
+    }
+
+    if let Some(s) = start {
+        if hits >= MIN_RUN && best.map(|b| hits > b.2).unwrap_or(true) {
+            best = Some((s, last_hit, hits));
+        }
+    }
+
+    best
+}
+
+/* Looks through the data that no override describes for runs of words that look like pointers
+   to labels, either in the data's own bank or in whichever bank explains the most of them */
+pub fn suggest_overrides(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<Suggestion> {
+    let labels = LABELS.lock().unwrap();
+    let label_addrs: BTreeSet<u64> = labels.keys().copied().collect();
+    let mut banks_of: BTreeMap<u16, Vec<u64>> = BTreeMap::new();
+    for addr in &label_addrs {
+        banks_of.entry((addr & 0xFFFF) as u16).or_default().push(addr >> 16);
+    }
+
+    /* Split the unclaimed data words into runs, broken up by gaps, labels and other value sizes */
+    let mut runs: Vec<Vec<(u64, u16)>> = Vec::new();
+    let mut run: Vec<(u64, u16)> = Vec::new();
+    for line in lines.values().flatten() {
+        let d = match line {
+            Line::Data(d) => d,
+            _ => continue
+        };
+
+        for (addr, val) in d.entries() {
+            let contiguous = run.last().map(|(a, _)| a + 2 == addr).unwrap_or(true);
+            let claimed = config.get_override(addr).is_some();
+            if !contiguous || claimed || label_addrs.contains(&addr) || !matches!(val, DataVal::DW(_)) {
+                runs.push(std::mem::take(&mut run));
+            }
+
+            if let DataVal::DW(w) = val {
+                if !claimed {
+                    run.push((addr, *w));
+                }
+            }
+        }
+    }
+    runs.push(run);
+
+    let mut suggestions = Vec::new();
+    for run in runs.iter().filter(|r| r.len() >= MIN_RUN) {
+        let own_bank = run[0].0 >> 16;
+        let mut banks: BTreeSet<u64> = run.iter().flat_map(|(_, w)| banks_of.get(w).cloned().unwrap_or_default()).collect();
+        banks.insert(own_bank);
+
+        /* The data's own bank wins ties */
+        let best = banks.iter()
+            .filter_map(|b| longest_stretch(run, *b, &label_addrs).map(|s| (*b, s)))
+            .max_by_key(|(b, (_, _, hits))| (*hits, *b == own_bank));
+
+        let (bank, (first, last, hits)) = match best {
+            Some(b) => b,
+            None => continue
+        };
+
+        let start = run[first].0;
+        let words = last - first + 1;
+        let table_label = labels.get(&start);
+        let same_type = run[first..=last].iter()
+            .filter_map(|(_, w)| labels.get(&((bank << 16) | *w as u64)))
+            .map(|l| std::mem::discriminant(&l.label_type))
+            .collect::<HashSet<_>>().len() == 1;
+        let code_targets = run[first..=last].iter()
+            .filter_map(|(_, w)| labels.get(&((bank << 16) | *w as u64)))
+            .all(|l| l.label_type == LabelType::Subroutine);
+
+        let mut score = 0;
+        let mut reasons = vec![format!("{} of {} words point at labels in bank ${:02X}", hits, words, bank)];
+        if bank == own_bank {
+            score += 1;
+        } else {
+            reasons.push("different bank".to_string());
+        }
+        if let Some(l) = table_label {
+            score += 1;
+            reasons.push(format!("starts at {}", l.name));
+        }
+        if same_type && hits >= 4 {
+            score += 1;
+            reasons.push(if code_targets { "all code targets".to_string() } else { "all targets alike".to_string() });
+        }
+
+        let confidence = match score {
+            3 => Confidence::High,
+            2 => Confidence::Medium,
+            _ => Confidence::Low
+        };
+
+        suggestions.push(Suggestion {
+            start,
+            end: run[last].0 + 1,
+            db: bank,
+            hits,
+            confidence,
+            reason: reasons.join(", ")
+        });
+    }
+
+    suggestions.sort_by(|a, b| b.confidence.cmp(&a.confidence)
+        .then(b.hits.cmp(&a.hits))
+        .then(a.start.cmp(&b.start)));
+    suggestions
+}
+
+pub fn print_suggestions(suggestions: &[Suggestion]) {
+    println!("# Suggested overrides for data that looks like pointers, most likely first.");
+    println!("# Check each one against the bank logs before pasting it into config/overrides.\n");
+
+    for s in suggestions {
+        println!("# {}: {}", s.confidence, s.reason);
+        println!("- addr: [0x{:06X}, 0x{:06X}]", s.start, s.end);
+        println!("  type: Pointer");
+        println!("  db: 0x{:02X}\n", s.db);
+    }
+}
@@ -0,0 +1,158 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt, fs::File, io::Write};
+
+use crate::{code::Code, config::{Config, Override, OverrideAddr}, incbin, jumptable, label::{LabelType, LABELS}, line::Line, opcode::AddrMode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    Low,
+    Medium,
+    High
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Confidence::Low => write!(f, "low"),
+            Confidence::Medium => write!(f, "medium"),
+            Confidence::High => write!(f, "high")
+        }
+    }
+}
+
+/* An indexed access to a table, with its element size and the index limit checked before it */
+struct Access {
+    size: Option<u64>,
+    limit: Option<u64>
+}
+
+#[derive(Debug)]
+pub struct TableInfo {
+    pub address: u64,
+    pub name: String,
+    pub pointer: bool,
+    pub elem_size: u64,
+    pub length: u64,
+    pub confidence: Confidence,
+    pub reason: &'static str
+}
+
+/* Width of the value an indexed instruction reads or writes */
+fn access_size(c: &Code) -> Option<u64> {
+    let flag = match c.opcode.name {
+        _ if c.opcode.addr_mode == AddrMode::AbsoluteIndexedIndirect => return Some(2),
+        "LDX" | "LDY" | "STX" | "STY" | "CPX" | "CPY" => c.index_8bit,
+        _ => c.acc_8bit
+    };
+    flag.map(|f| if f { 1 } else { 2 })
+}
+
+/* Fills in the length of the auto-generated pointer and data tables. The element size comes from
+   the width of the accesses, the length from any index check before them and from the distance
+   to whatever follows the table. */
+pub fn infer_lengths(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> Vec<TableInfo> {
+    let code: Vec<&Code> = lines.values().flatten().filter_map(|l| match l {
+        Line::Code(c) => Some(c),
+        _ => None
+    }).collect();
+    let code_addrs: BTreeSet<u64> = code.iter().map(|c| c.address).collect();
+
+    let mut labels = LABELS.lock().unwrap();
+    let tables: BTreeSet<u64> = labels.values()
+        .filter(|l| matches!(l.label_type, LabelType::PointerTable(0) | LabelType::DataTable(0)))
+        .map(|l| l.address)
+        .collect();
+    let label_addrs: BTreeSet<u64> = labels.keys().copied().collect();
+
+    let mut accesses: BTreeMap<u64, Vec<Access>> = BTreeMap::new();
+    for (i, c) in code.iter().enumerate() {
+        let indexed = matches!(c.opcode.addr_mode,
+            AddrMode::AbsoluteIndexedX | AddrMode::AbsoluteIndexedY | AddrMode::AbsoluteIndexedLong | AddrMode::AbsoluteIndexedIndirect);
+        if !indexed {
+            continue;
+        }
+
+        if let Some(addr) = c.operand_addr(config).filter(|a| tables.contains(a)) {
+            let index = if c.opcode.addr_mode == AddrMode::AbsoluteIndexedY { 'Y' } else { 'X' };
+            let limit = jumptable::index_limit(&code[i.saturating_sub(12)..i], index);
+            accesses.entry(addr).or_default().push(Access { size: access_size(c), limit });
+        }
+    }
+
+    let mut infos = Vec::new();
+    for (addr, access) in accesses {
+        let label = labels.get_mut(&addr).unwrap();
+        let pointer = matches!(label.label_type, LabelType::PointerTable(_));
+        let elem_size = access.iter().filter_map(|a| a.size).max().unwrap_or(2);
+        let limit = access.iter().filter_map(|a| a.limit).max();
+
+        let next_label = label_addrs.range(addr + 1..).next().copied();
+        let next_code = code_addrs.range(addr + 1..).next().copied();
+        /* The distance only means something when the table is part of the listing */
+        let distance = [next_label, next_code].iter().flatten()
+            .filter(|_| lines.contains_key(&addr))
+            .filter(|a| *a >> 16 == addr >> 16)
+            .min()
+            .map(|a| (a - addr) / elem_size)
+            .filter(|d| *d > 0);
+        let bound = limit.map(|l| l.div_ceil(elem_size));
+
+        let (length, confidence, reason) = match (bound, distance) {
+            (Some(b), Some(d)) if b == d => (b, Confidence::High, "index check matches the distance to the next label"),
+            (Some(b), Some(d)) if b < d => (b, Confidence::Medium, "index check, ends before the next label"),
+            (Some(_), Some(d)) => (d, Confidence::Low, "index check runs past the next label, cut off there"),
+            (Some(b), None) => (b, Confidence::Medium, "index check"),
+            (None, Some(d)) => (d, Confidence::Low, "distance to the next label"),
+            (None, None) => continue
+        };
+
+        label.label_type = if pointer { LabelType::PointerTable(length) } else { LabelType::DataTable(length) };
+        infos.push(TableInfo { address: addr, name: label.name.to_string(), pointer, elem_size, length, confidence, reason });
+    }
+
+    infos
+}
+
+/* Pointer overrides for the pointer tables nothing else has sized, so their entries get labelled. Only the
+   lengths an index check backs up are used, the distance to the next label alone is too much of a guess,
+   and the table stops at the first entry that doesn't point at code. */
+pub fn overrides(lines: &BTreeMap<u64, Vec<Line>>, infos: &[TableInfo], config: &Config) -> Vec<Override> {
+    let code_addrs: BTreeSet<u64> = lines.values().flatten().filter_map(|l| match l {
+        Line::Code(c) => Some(c.address),
+        _ => None
+    }).collect();
+
+    infos.iter()
+        .filter(|t| t.pointer && t.elem_size == 2 && t.confidence >= Confidence::Medium)
+        .filter(|t| !config.overrides.iter().any(|o| o.contains(t.address)))
+        .filter_map(|t| {
+            let count = incbin::read_bytes(lines, t.address, t.length * 2)
+                .chunks_exact(2)
+                .take_while(|w| code_addrs.contains(&((t.address & 0xFF0000) | w[0] as u64 | ((w[1] as u64) << 8))))
+                .count() as u64;
+            Some(Override {
+                addr: OverrideAddr::Range(vec![t.address, t.address + count * 2 - 1]),
+                db: Some(t.address >> 16),
+                _type: Some("Pointer".to_string()),
+                ..Default::default()
+            }).filter(|_| count > 0)
+        })
+        .collect()
+}
+
+/* Writes the inferred tables as label entries that can be copied into the label config */
+pub fn write_suggestions(infos: &[TableInfo], filename: &str) {
+    let mut output_file = File::create(filename).unwrap();
+    let _ = writeln!(output_file, "# Table lengths inferred from the code. Copy entries into config/labels to keep them.");
+    let _ = writeln!(output_file, "# Lengths are in 16-bit entries, tables of bytes are only listed as comments.\n");
+
+    for t in infos {
+        let entry = format!("- {{ addr: 0x{:06X}, name: {}, type: {}, length: {} }}",
+            t.address, t.name, if t.pointer { "PointerTable" } else { "DataTable" }, t.length);
+        let _ = writeln!(output_file, "# confidence: {} ({})", t.confidence, t.reason);
+        if t.elem_size == 2 {
+            let _ = writeln!(output_file, "{}", entry);
+        } else {
+            let _ = writeln!(output_file, "# byte entries: {}", entry);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::{collections::BTreeMap, fmt, fs::File, io::{BufWriter, Write}, path::Path};
+
+use crate::{config::{Config, Override, OverrideAddr}, incbin::{self, Binary}, label::LABELS, line::Line, palette};
+
+/* Tiles across a sheet */
+const SHEET_WIDTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TilesError {
+    NotARange { address: u64 },
+    Length { address: u64, length: u64, bpp: usize },
+    MissingData { address: u64 },
+    LocalLabel { address: u64 }
+}
+
+impl fmt::Display for TilesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TilesError::NotARange { address } => write!(f, "${:06X}: tiles need a range, left as they are", address),
+            TilesError::Length { address, length, bpp } =>
+                write!(f, "${:06X}: ${:X} bytes isn't a whole number of {}bpp tiles, left as they are", address, length, bpp),
+            TilesError::MissingData { address } => write!(f, "${:06X}: not all of the tiles are in the log, left as they are", address),
+            TilesError::LocalLabel { address } => write!(f, "${:06X}: has a local label inside, left as they are", address)
+        }
+    }
+}
+
+/* Bits per pixel of the tile overrides */
+fn bpp(ov: &Override) -> Option<usize> {
+    match ov._type.as_deref() {
+        Some("Tiles2bpp") => Some(2),
+        Some("Tiles4bpp") => Some(4),
+        Some("Tiles8bpp") => Some(8),
+        _ => None
+    }
+}
+
+/* Where bit plane "plane" of row "y" is in a tile: planes come in pairs, a row of each pair after the other */
+fn plane_offset(plane: usize, y: usize) -> usize {
+    (plane / 2) * 16 + y * 2 + (plane % 2)
+}
+
+/* Tiles to pixels of a sheet SHEET_WIDTH tiles across, the colour index of each */
+fn decode(bytes: &[u8], bpp: usize) -> (usize, usize, Vec<u8>) {
+    let count = bytes.len() / (8 * bpp);
+    let (width, height) = (SHEET_WIDTH * 8, count.div_ceil(SHEET_WIDTH) * 8);
+    let mut pixels = vec![0; width * height];
+    for (t, tile) in bytes.chunks_exact(8 * bpp).enumerate() {
+        let (tx, ty) = ((t % SHEET_WIDTH) * 8, (t / SHEET_WIDTH) * 8);
+        for y in 0..8 {
+            for x in 0..8 {
+                let index = (0..bpp).map(|p| ((tile[plane_offset(p, y)] >> (7 - x)) & 1) << p).sum();
+                pixels[(ty + y) * width + tx + x] = index;
+            }
+        }
+    }
+    (width, height, pixels)
+}
+
+/* The other way around, for "count" tiles */
+fn encode(pixels: &[u8], width: usize, bpp: usize, count: usize) -> Vec<u8> {
+    let mut bytes = vec![0; count * 8 * bpp];
+    for (t, tile) in bytes.chunks_exact_mut(8 * bpp).enumerate() {
+        let (tx, ty) = ((t % SHEET_WIDTH) * 8, (t / SHEET_WIDTH) * 8);
+        for y in 0..8 {
+            for x in 0..8 {
+                let index = pixels[(ty + y) * width + tx + x];
+                for p in 0..bpp {
+                    tile[plane_offset(p, y)] |= ((index >> p) & 1) << (7 - x);
+                }
+            }
+        }
+    }
+    bytes
+}
+
+/* The colours the sheet gets: the palette label's if there's one, as far as its Palette override goes, greys otherwise */
+fn colors(lines: &BTreeMap<u64, Vec<Line>>, config: &Config, ov: &Override, bpp: usize) -> Vec<[u8; 3]> {
+    let count: u64 = 1 << bpp;
+    let address = ov.palette.as_ref().and_then(|name| {
+        LABELS.lock().unwrap().values().find(|l| l.name == *name).map(|l| l.address)
+    });
+    let limit = |a: u64| config.overrides.iter()
+        .find(|p| p._type.as_deref() == Some("Palette") && p.contains(a))
+        .map(palette::region)
+        .map(|(start, length)| (start + length - a).min(count * 2))
+        .unwrap_or(count * 2);
+    let mut colors: Vec<[u8; 3]> = address
+        .map(|a| incbin::read_bytes(lines, a, limit(a)))
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|c| palette::to_rgb(c[0] as u16 | ((c[1] as u16) << 8)))
+        .collect();
+    if colors.is_empty() {
+        colors = (0..count).map(|i| [(i * 255 / (count - 1)) as u8; 3]).collect();
+    }
+    colors.resize(count as usize, [0, 0, 0]);
+    colors
+}
+
+fn write_png(path: &str, width: usize, height: usize, pixels: &[u8], colors: &[[u8; 3]]) -> Result<(), png::EncodingError> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(colors.concat());
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)
+}
+
+/* The colour indexes of an indexed PNG, whatever bit depth it was saved with */
+fn read_png(path: &Path) -> Result<(usize, Vec<u8>), String> {
+    let mut decoder = png::Decoder::new(File::open(path).map_err(|e| e.to_string())?);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    if info.color_type != png::ColorType::Indexed {
+        return Err("isn't an indexed PNG anymore".to_string());
+    }
+
+    let depth = info.bit_depth as usize;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let pixels = (0..height).flat_map(|y| {
+        let row = &buffer[y * info.line_size..(y + 1) * info.line_size];
+        (0..width).map(move |x| {
+            let bit = x * depth;
+            (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8
+        })
+    }).collect();
+    Ok((width, pixels))
+}
+
+/* Writes the tiles of each Tiles2bpp/4bpp/8bpp override to "<folder>/<label>.png", a sheet with the colours
+   of the label in the override's "palette" if there's one. The tiles themselves go to "<label>.2bpp" (or
+   4bpp/8bpp), which the bank files include and the "tiles" command makes from the PNGs again. */
+pub fn extract(lines: &mut BTreeMap<u64, Vec<Line>>, config: &Config, folder: &str) -> Vec<TilesError> {
+    let mut errors = Vec::new();
+    for ov in &config.overrides {
+        let Some(bpp) = bpp(ov) else {
+            continue;
+        };
+        let (start, length) = match &ov.addr {
+            OverrideAddr::Range(r) => (r[0], r[1] - r[0] + 1),
+            OverrideAddr::Address(a) => {
+                errors.push(TilesError::NotARange { address: *a });
+                continue;
+            }
+        };
+        if length % (8 * bpp as u64) != 0 {
+            errors.push(TilesError::Length { address: start, length, bpp });
+            continue;
+        }
+        let bytes = incbin::read_bytes(lines, start, length);
+        if (bytes.len() as u64) < length {
+            errors.push(TilesError::MissingData { address: start });
+            continue;
+        }
+        let (base, inner) = match incbin::region_labels(start, start + length, "GFX") {
+            Some(l) => l,
+            None => {
+                errors.push(TilesError::LocalLabel { address: start });
+                continue;
+            }
+        };
+
+        std::fs::create_dir_all(folder).unwrap();
+        let name = base.trim_start_matches('.').to_string();
+        let (width, height, pixels) = decode(&bytes, bpp);
+        let _ = write_png(&format!("{}/{}.png", folder, name), width, height, &pixels, &colors(lines, config, ov, bpp));
+        let mut output_file = File::create(format!("{}/{}.{}bpp", folder, name, bpp)).unwrap();
+        let _ = output_file.write_all(&bytes);
+
+        incbin::replace(lines, start, start + length, vec![Line::Binary(Binary {
+            directive: "incbin", base, file: format!("gfx/{}.{}bpp", name, bpp), labels: inner
+        })]);
+    }
+    errors
+}
+
+/* The build step: the tiles of every PNG that has a .2bpp/.4bpp/.8bpp next to it are made again, keeping
+   the tile count. Those that come out different are written over the old ones. */
+pub fn import(folder: &str, filename: &str) {
+    let mut report_file = File::create(filename).unwrap();
+    let mut files: Vec<_> = glob::glob(&format!("{}/*.png", folder)).unwrap().flatten().collect();
+    files.sort();
+    for png_path in files {
+        let name = png_path.file_stem().unwrap().to_string_lossy().to_string();
+        let Some((bpp, tiles_path)) = [2, 4, 8].iter()
+            .map(|b| (*b, png_path.with_extension(format!("{}bpp", b))))
+            .find(|(_, p)| p.exists()) else {
+            continue;
+        };
+        let old = std::fs::read(&tiles_path).unwrap_or_default();
+        let count = old.len() / (8 * bpp);
+
+        let result = read_png(&png_path).and_then(|(width, pixels)| {
+            if width != SHEET_WIDTH * 8 || pixels.len() < count.div_ceil(SHEET_WIDTH) * 64 * SHEET_WIDTH {
+                Err(format!("isn't {} pixels wide with room for {} tiles", SHEET_WIDTH * 8, count))
+            } else if pixels.iter().any(|p| *p as usize >= 1 << bpp) {
+                Err(format!("uses colours past the first {}", 1 << bpp))
+            } else {
+                Ok(encode(&pixels, width, bpp, count))
+            }
+        });
+
+        let _ = match result {
+            Ok(bytes) if bytes == old => writeln!(report_file, "{}: unchanged", name),
+            Ok(bytes) => {
+                let mut output_file = File::create(&tiles_path).unwrap();
+                let _ = output_file.write_all(&bytes);
+                writeln!(report_file, "{}: rebuilt", name)
+            },
+            Err(e) => writeln!(report_file, "{}: {}, left as it was", name, e)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Bytes that look like nothing in particular */
+    fn noise(length: usize) -> Vec<u8> {
+        let mut seed: u32 = 7;
+        (0..length).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn tiles_round_trip() {
+        for bpp in [2, 4, 8] {
+            /* A sheet with a partly filled last row */
+            let count = SHEET_WIDTH + 3;
+            let bytes = noise(count * 8 * bpp);
+            let (width, height, pixels) = decode(&bytes, bpp);
+            assert_eq!((width, height), (SHEET_WIDTH * 8, 16));
+            assert!(pixels.iter().all(|p| (*p as usize) < 1 << bpp));
+            assert_eq!(encode(&pixels, width, bpp, count), bytes);
+        }
+    }
+
+    #[test]
+    fn planes() {
+        /* 2bpp: plane 0 of the first row sets its first pixel, plane 1 of the second row its last */
+        let mut bytes = vec![0; 16];
+        bytes[0] = 0x80;
+        bytes[3] = 0x01;
+        let (width, _, pixels) = decode(&bytes, 2);
+        assert_eq!(pixels[0], 1);
+        assert_eq!(pixels[width + 7], 2);
+        assert_eq!(pixels.iter().filter(|p| **p != 0).count(), 2);
+    }
+
+    #[test]
+    fn png_bit_depths() {
+        let (width, height) = (SHEET_WIDTH * 8, 8);
+        for (depth, bits) in [(png::BitDepth::One, 1), (png::BitDepth::Two, 2), (png::BitDepth::Four, 4), (png::BitDepth::Eight, 8)] {
+            let pixels: Vec<u8> = noise(width * height).iter().map(|p| p & ((1u16 << bits) - 1) as u8).collect();
+            let packed: Vec<u8> = pixels.chunks(8 / bits).map(|c| {
+                c.iter().enumerate().fold(0, |byte, (i, p)| byte | (p << (8 - bits * (i + 1))))
+            }).collect();
+
+            let path = std::env::temp_dir().join(format!("pjdasm_tiles_{}.png", bits));
+            let mut encoder = png::Encoder::new(File::create(&path).unwrap(), width as u32, height as u32);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(depth);
+            encoder.set_palette(vec![0; 3 << bits]);
+            encoder.write_header().unwrap().write_image_data(&packed).unwrap();
+
+            assert_eq!(read_png(&path).unwrap(), (width, pixels));
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{collections::BTreeMap, fmt, fs::File, io::Write};
+
+use crate::{config::Config, label::LABELS, line::Line, opcode::AddrMode};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XrefKind {
+    Call(&'static str),
+    Jump,
+    Read,
+    Write,
+    Pointer
+}
+
+impl fmt::Display for XrefKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XrefKind::Call(op) => write!(f, "{}", op),
+            XrefKind::Jump => write!(f, "jump"),
+            XrefKind::Read => write!(f, "read"),
+            XrefKind::Write => write!(f, "write"),
+            XrefKind::Pointer => write!(f, "pointer")
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Xref {
+    pub from: u64,
+    pub kind: XrefKind
+}
+
+/* Every reference to a label, keyed on the label's address */
+pub fn build(lines: &BTreeMap<u64, Vec<Line>>, config: &Config) -> BTreeMap<u64, Vec<Xref>> {
+    let mut xrefs: BTreeMap<u64, Vec<Xref>> = BTreeMap::new();
+
+    for line in lines.values().flatten() {
+        match line {
+            Line::Code(c) => {
+                if let Some((label_addr, _)) = c.operand_label(config) {
+                    let kind = match (c.opcode.name, &c.opcode.addr_mode) {
+                        ("JSR", _) | ("JSL", _) => XrefKind::Call(c.opcode.name),
+                        (_, AddrMode::Relative) | (_, AddrMode::RelativeLong) if c.opcode.name != "PER" => XrefKind::Jump,
+                        ("JMP", _) | ("JML", _) => XrefKind::Jump,
+                        (_, AddrMode::Immediate) | ("PEA", _) | ("PER", _) => XrefKind::Pointer,
+                        ("STA", _) | ("STX", _) | ("STY", _) | ("STZ", _) | ("TSB", _) | ("TRB", _) |
+                        ("INC", _) | ("DEC", _) | ("ASL", _) | ("LSR", _) | ("ROL", _) | ("ROR", _) => XrefKind::Write,
+                        _ => XrefKind::Read
+                    };
+                    xrefs.entry(label_addr).or_default().push(Xref { from: c.address, kind });
+                }
+            },
+            Line::Data(d) => {
+                for (from, label_addr) in d.pointers(config) {
+                    xrefs.entry(label_addr).or_default().push(Xref { from, kind: XrefKind::Pointer });
+                }
+            },
+            _ => ()
+        }
+    }
+
+    xrefs
+}
+
+/* Names a referencing address relative to the closest global label before it, like "Foo+12" */
+fn location_names(xrefs: &BTreeMap<u64, Vec<Xref>>) -> BTreeMap<u64, String> {
+    let labels = LABELS.lock().unwrap();
+    let globals: BTreeMap<u64, &String> = labels.iter()
+        .filter(|(_, l)| !l.name.starts_with('.'))
+        .map(|(a, l)| (*a, &l.name))
+        .collect();
+
+    xrefs.values().flatten().map(|x| {
+        let name = match globals.range(..=x.from).next_back() {
+            Some((a, n)) if a >> 16 == x.from >> 16 && *a == x.from => n.to_string(),
+            Some((a, n)) if a >> 16 == x.from >> 16 => format!("{}+{}", n, x.from - a),
+            _ => format!("${:06X}", x.from)
+        };
+        (x.from, name)
+    }).collect()
+}
+
+pub struct XrefComments {
+    comments: BTreeMap<u64, String>
+}
+
+impl XrefComments {
+    pub fn new(xrefs: &BTreeMap<u64, Vec<Xref>>) -> XrefComments {
+        let names = location_names(xrefs);
+        let comments = xrefs.iter().map(|(addr, refs)| {
+            let list: Vec<String> = refs.iter().map(|x| format!("{} ({})", names[&x.from], x.kind)).collect();
+            (*addr, format!("; xref: {}", list.join(", ")))
+        }).collect();
+        XrefComments { comments }
+    }
+
+    pub fn get(&self, addr: u64) -> Option<&String> {
+        self.comments.get(&addr)
+    }
+}
+
+pub fn write_report(xrefs: &BTreeMap<u64, Vec<Xref>>, filename: &str) {
+    let names = location_names(xrefs);
+    let labels = LABELS.lock().unwrap();
+    let mut output_file = File::create(filename).unwrap();
+
+    for (addr, refs) in xrefs {
+        let _ = writeln!(output_file, "{} (${:06X})", labels.get(addr).map(|l| l.name.as_str()).unwrap_or("?"), addr);
+        for x in refs {
+            let _ = writeln!(output_file, "    ${:06X}  {:<40} {}", x.from, names[&x.from], x.kind);
+        }
+    }
+}